@@ -0,0 +1,147 @@
+//! Prints what the GBM backend of one or more DRM nodes can allocate.
+//!
+//! Usage: `gbm-info [--json] [--all] [DEVICE...]`
+//!
+//! Without a device argument every `/dev/dri/renderD*` node is probed.
+
+extern crate gbm;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process;
+
+use gbm::{write_json_str, Capabilities, Device};
+
+struct Options {
+    json: bool,
+    all: bool,
+    devices: Vec<PathBuf>,
+}
+
+fn usage() -> ! {
+    let _ = writeln!(io::stderr(), "usage: gbm-info [--json] [--all] [DEVICE...]");
+    process::exit(2)
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options { json: false, all: false, devices: Vec::new() };
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--json" => opts.json = true,
+            "--all" => opts.all = true,
+            "-h" | "--help" => usage(),
+            s if s.starts_with('-') => usage(),
+            _ => opts.devices.push(PathBuf::from(arg)),
+        }
+    }
+    opts
+}
+
+fn render_nodes() -> io::Result<Vec<PathBuf>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir("/dev/dri")? {
+        let path = entry?.path();
+        let is_render = path.file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with("renderD"));
+        if is_render {
+            nodes.push(path);
+        }
+    }
+    nodes.sort();
+    Ok(nodes)
+}
+
+fn probe(path: &PathBuf) -> io::Result<Capabilities> {
+    let file: File = OpenOptions::new().read(true).write(true).open(path)?;
+    let dev = Device::create(file.as_raw_fd()).map_err(io::Error::other)?;
+    let caps = dev.capabilities();
+    // The device must go before the fd it was created from.
+    drop(dev);
+    drop(file);
+    Ok(caps)
+}
+
+fn print_matrix<W: Write>(w: &mut W, caps: &Capabilities, all: bool) -> io::Result<()> {
+    let columns = Capabilities::usage_names();
+    writeln!(w, "backend: {}", caps.backend)?;
+    write!(w, "{:<14}{:<6}", "format", "code")?;
+    for c in &columns {
+        write!(w, "{:>10}", c)?;
+    }
+    writeln!(w, "  modifiers (planes)")?;
+    for f in &caps.formats {
+        if !all && !f.is_supported() {
+            continue;
+        }
+        let usage = f.usage_names();
+        write!(w, "{:<14}{:<6}", f.format.name(), f.format.code())?;
+        for c in &columns {
+            write!(w, "{:>10}", if usage.contains(c) { "x" } else { "-" })?;
+        }
+        for m in &f.modifiers {
+            write!(w, "  0x{:016x}({})", m.modifier, m.plane_count)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let opts = parse_args();
+    let devices = if opts.devices.is_empty() {
+        match render_nodes() {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "gbm-info: /dev/dri: {}", e);
+                process::exit(1)
+            }
+        }
+    } else {
+        opts.devices.clone()
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut failed = false;
+    let mut first = true;
+    if opts.json {
+        let _ = write!(out, "[");
+    }
+    for path in &devices {
+        let caps = match probe(path) {
+            Ok(caps) => caps,
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "gbm-info: {}: {}", path.display(), e);
+                failed = true;
+                continue;
+            }
+        };
+        let res = if opts.json {
+            let sep = if first { "" } else { "," };
+            write!(out, "{}{{\"device\":", sep)
+                .and_then(|_| write_json_str(&mut out, &path.display().to_string()))
+                .and_then(|_| write!(out, ",\"capabilities\":"))
+                .and_then(|_| caps.write_json(&mut out))
+                .and_then(|_| write!(out, "}}"))
+        } else {
+            let sep = if first { "" } else { "\n" };
+            writeln!(out, "{}{}", sep, path.display())
+                .and_then(|_| print_matrix(&mut out, &caps, opts.all))
+        };
+        first = false;
+        if let Err(e) = res {
+            let _ = writeln!(io::stderr(), "gbm-info: {}", e);
+            process::exit(1)
+        }
+    }
+    if opts.json {
+        let _ = writeln!(out, "]");
+    }
+    if failed || devices.is_empty() {
+        process::exit(1)
+    }
+}
@@ -0,0 +1,111 @@
+//! Panic containment for callbacks handed to libgbm.
+//!
+//! Unwinding out of an `extern fn` into C is undefined behaviour, so
+//! every callback runs inside `guard`.  A caught panic is stashed on the
+//! current thread, and the Rust wrapper whose call made libgbm run the
+//! callback (`Bo::drop`, `Surface::drop`, ...) passes it on with
+//! `resume` once libgbm has returned.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+
+/// Receives panics from callbacks instead of having them re-raised.
+pub type CallbackPanicHook = fn(Box<dyn Any + Send>);
+
+static HOOK: Mutex<Option<CallbackPanicHook>> = Mutex::new(None);
+
+thread_local! {
+    static PENDING: RefCell<Option<Box<dyn Any + Send>>> = RefCell::new(None);
+}
+
+/// Installs a hook that is given every panic caught in a libgbm
+/// callback, or removes it with `None`.  Without a hook the panic is
+/// re-raised from the Rust call that triggered the callback, unless that
+/// call is itself running during a panic, in which case the payload is
+/// dropped.
+pub fn set_callback_panic_hook(hook: Option<CallbackPanicHook>) {
+    *HOOK.lock().unwrap_or_else(|e| e.into_inner()) = hook;
+}
+
+/// Runs `f`, stashing any panic instead of letting it unwind.  Only the
+/// first panic is kept until the next `resume`.
+pub fn guard<F: FnOnce()>(f: F) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+        PENDING.with(|p| {
+            let mut p = p.borrow_mut();
+            if p.is_none() {
+                *p = Some(payload);
+            }
+        });
+    }
+}
+
+/// Passes on a panic stashed by `guard` on this thread, if there is one.
+pub fn resume() {
+    let payload = match PENDING.with(|p| p.borrow_mut().take()) {
+        Some(payload) => payload,
+        None => return,
+    };
+    let hook = *HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    match hook {
+        Some(hook) => hook(payload),
+        None if !thread::panicking() => panic::resume_unwind(payload),
+        None => drop(payload),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The hook is global, so tests touching it must not overlap.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    static HOOKED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn record(payload: Box<dyn Any + Send>) {
+        let msg = *payload.downcast::<&str>().unwrap();
+        HOOKED.lock().unwrap().push(msg.to_owned());
+    }
+
+    fn pending() -> Option<String> {
+        PENDING.with(|p| p.borrow().as_ref().map(|p| p.downcast_ref::<&str>().unwrap().to_string()))
+    }
+
+    #[test]
+    fn no_panic() {
+        let mut ran = false;
+        guard(|| ran = true);
+        assert!(ran);
+        assert_eq!(pending(), None);
+        resume();
+    }
+
+    #[test]
+    fn panic_held_then_resumed() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        guard(|| panic!("first"));
+        guard(|| panic!("second"));
+        assert_eq!(pending().as_ref().map(|s| &s[..]), Some("first"));
+
+        let payload = panic::catch_unwind(resume).unwrap_err();
+        assert_eq!(*payload.downcast::<&str>().unwrap(), "first");
+        assert_eq!(pending(), None);
+        // Nothing left to re-raise.
+        resume();
+    }
+
+    #[test]
+    fn hook_called() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        set_callback_panic_hook(Some(record));
+        guard(|| panic!("hooked"));
+        resume();
+        set_callback_panic_hook(None);
+        assert_eq!(pending(), None);
+        assert_eq!(*HOOKED.lock().unwrap(), ["hooked"]);
+    }
+}
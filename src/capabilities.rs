@@ -0,0 +1,194 @@
+//! Capability reports describing which formats, usages and modifiers a
+//! `Device` can allocate.
+
+use std::fmt;
+use std::io;
+
+use {ffi, BoFlags, BoFormat, Device, FourCC};
+
+/// Everything `Device::capabilities` found out about a device.
+///
+/// Modifiers are probed from `ffi::KNOWN_MODIFIERS` only.  AMD modifiers
+/// carrying DCC or the device's pipe and bank XOR bits, and NVIDIA's
+/// kind and page layout specific ones, are not in that list and so never
+/// show up here even when the device supports them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Capabilities {
+    pub backend: String,
+    /// One entry per known fourcc, supported or not.
+    pub formats: Vec<FormatCapabilities>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FormatCapabilities {
+    pub format: FourCC,
+    /// Union of the usage flags accepted individually for this format.
+    pub usage: BoFlags,
+    pub modifiers: Vec<ModifierInfo>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ModifierInfo {
+    #[cfg_attr(feature = "serde", serde(with = "::serde_impls::modifier"))]
+    pub modifier: u64,
+    pub plane_count: u32,
+}
+
+impl Capabilities {
+    pub fn probe(dev: &Device) -> Capabilities {
+        let formats = FourCC::ALL.iter().map(|&format| {
+            let mut usage = BoFlags::new();
+            for &(_, flag) in BoFlags::named() {
+                if dev.is_format_supported(BoFormat::FourCC(format), flag) {
+                    usage.insert(flag);
+                }
+            }
+            let modifiers = ffi::KNOWN_MODIFIERS.iter().filter_map(|&modifier| {
+                dev.format_modifier_plane_count(format, modifier)
+                    .map(|plane_count| ModifierInfo {
+                        modifier,
+                        plane_count,
+                    })
+            }).collect();
+            FormatCapabilities {
+                format,
+                usage,
+                modifiers,
+            }
+        }).collect();
+
+        Capabilities {
+            backend: dev.get_backend_name().to_owned(),
+            formats,
+        }
+    }
+
+    /// Names of every usage flag probed, in bit order.  These are the
+    /// columns of the format × usage matrix.
+    pub fn usage_names() -> Vec<&'static str> {
+        BoFlags::named().iter().map(|&(name, _)| name).collect()
+    }
+
+    /// Formats that support at least one usage or modifier.
+    pub fn supported(&self) -> Vec<&FormatCapabilities> {
+        self.formats.iter().filter(|f| f.is_supported()).collect()
+    }
+
+    /// Writes the report as a single JSON object, in the same shape as
+    /// its `Serialize` implementation with the `serde` feature: fourccs
+    /// as their code, usage as a list of flag names, modifiers in hex.
+    pub fn write_json<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{{\"backend\":")?;
+        write_json_str(w, &self.backend)?;
+        write!(w, ",\"formats\":[")?;
+        for (i, f) in self.formats.iter().enumerate() {
+            if i > 0 { write!(w, ",")? }
+            write!(w, "{{\"format\":")?;
+            write_json_str(w, f.format.code().trim_end())?;
+            write!(w, ",\"usage\":[")?;
+            let names = if f.usage.is_empty() { String::new() } else { f.usage.to_string() };
+            for (j, name) in names.split('|').filter(|n| !n.is_empty()).enumerate() {
+                if j > 0 { write!(w, ",")? }
+                write_json_str(w, name)?;
+            }
+            write!(w, "],\"modifiers\":[")?;
+            for (j, m) in f.modifiers.iter().enumerate() {
+                if j > 0 { write!(w, ",")? }
+                write!(w, "{{\"modifier\":\"{:#018x}\",\"plane_count\":{}}}",
+                            m.modifier, m.plane_count)?;
+            }
+            write!(w, "]}}")?;
+        }
+        write!(w, "]}}")
+    }
+}
+
+impl FormatCapabilities {
+    pub fn is_supported(&self) -> bool {
+        !self.usage.is_empty() || !self.modifiers.is_empty()
+    }
+
+    /// Names of the usage flags in `usage`, in bit order.
+    pub fn usage_names(&self) -> Vec<&'static str> {
+        BoFlags::named().iter()
+            .filter(|&&(_, flag)| self.usage.contains(flag))
+            .map(|&(name, _)| name)
+            .collect()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backend: {}", self.backend)?;
+        for format in self.supported() {
+            write!(f, "{:<12} {}  [{}]", format.format.name(),
+                        format.format.code(), format.usage_names().join(" "))?;
+            for m in &format.modifiers {
+                write!(f, " 0x{:016x}/{}", m.modifier, m.plane_count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `s` as a JSON string, escaped as RFC 8259 requires.
+pub fn write_json_str<W: io::Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write!(w, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    write!(w, "\"")
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+
+    #[test]
+    fn json_matches_serde() {
+        let caps = Capabilities {
+            backend: "dri \"test\"\n".to_owned(),
+            formats: vec![
+                FormatCapabilities {
+                    format: FourCC::XRGB8888,
+                    usage: BoFlags::SCANOUT | BoFlags::RENDERING | BoFlags::from_bits(1 << 30),
+                    modifiers: vec![
+                        ModifierInfo { modifier: ffi::DRM_FORMAT_MOD_LINEAR, plane_count: 1 },
+                        ModifierInfo { modifier: ffi::I915_FORMAT_MOD_Y_TILED_CCS, plane_count: 2 },
+                    ],
+                },
+                FormatCapabilities {
+                    format: FourCC::C8,
+                    usage: BoFlags::empty(),
+                    modifiers: Vec::new(),
+                },
+            ],
+        };
+        let mut json = Vec::new();
+        caps.write_json(&mut json).unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(written, serde_json::to_value(&caps).unwrap());
+        let back: Capabilities = serde_json::from_slice(&json).unwrap();
+        assert_eq!(back, caps);
+    }
+
+    #[test]
+    fn json_strings_escaped() {
+        for s in &["/dev/dri/renderD128", "a\\b\"c", "tab\tnul\0\u{1f}end", "ünï"] {
+            let mut json = Vec::new();
+            write_json_str(&mut json, s).unwrap();
+            assert_eq!(serde_json::from_slice::<String>(&json).unwrap(), *s);
+        }
+    }
+}
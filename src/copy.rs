@@ -0,0 +1,222 @@
+//! CPU copies between buffers, as a fallback when the GPU can't import
+//! one of them.
+
+use pattern::{PlaneMut, PlaneRef};
+use pixel::{self, Layout};
+use {Bo, Error, FourCC, Rect, Transfer};
+
+/// Whether a region at (`x`, `y`) of `format` `width` pixels wide starts
+/// and ends on whole bytes and chroma blocks in every plane, so rows can
+/// be copied as is.
+fn block_aligned(format: FourCC, x: i32, y: i32, width: u32) -> bool {
+    // Pixel pairs of packed 4:2:2 formats share their chroma.
+    let packed_422 = format.planes().len() == 1 && format.planes()[0].hsub == 2;
+    if packed_422 && (x % 2 != 0 || !width.is_multiple_of(2)) {
+        return false;
+    }
+    format.planes().iter().all(|p| x % p.hsub as i32 == 0 && y % p.vsub as i32 == 0)
+}
+
+/// Clips copying `src_rect` of a `src_size` image to `dst` in a
+/// `dst_size` image against both, moving the other side along.  Returns
+/// the source origin and the destination rectangle, or `None` if nothing
+/// is left.
+fn clip(src_size: (u32, u32), src_rect: Rect, dst_size: (u32, u32),
+        dst: (i32, i32)) -> Option<((i32, i32), Rect)> {
+    let s = src_rect.intersection(&Rect::new(0, 0, src_size.0, src_size.1))?;
+    let d = Rect::new(dst.0 + (s.x - src_rect.x), dst.1 + (s.y - src_rect.y),
+                      s.width, s.height);
+    let clipped = d.intersection(&Rect::new(0, 0, dst_size.0, dst_size.1))?;
+    Some(((s.x + (clipped.x - d.x), s.y + (clipped.y - d.y)), clipped))
+}
+
+/// Copies the `d` sized region at `src_at` of `src` to `d` in `dst`, both
+/// already clipped and checked against the formats' layouts.
+fn copy_planes(src_format: FourCC, src: &[PlaneRef], src_at: (i32, i32),
+               dst_format: FourCC, dst: &mut [PlaneMut], d: Rect) -> Result<(), Error> {
+    let (sx, sy) = src_at;
+    if src_format == dst_format && block_aligned(src_format, sx, sy, d.width)
+        && block_aligned(dst_format, d.x, d.y, d.width)
+    {
+        for ((from, to), info) in src.iter().zip(dst).zip(src_format.planes()) {
+            let len = info.row_bytes(d.width) as usize;
+            let (sx, sy) = (info.row_bytes(sx as u32) as usize, (sy as u32 / info.vsub) as usize);
+            let (dx, dy) = (info.row_bytes(d.x as u32) as usize, (d.y as u32 / info.vsub) as usize);
+            for row in 0..info.rows(d.height) as usize {
+                let from_at = (sy + row) * from.stride + sx;
+                let to_at = (dy + row) * to.stride + dx;
+                to.data[to_at..to_at + len].copy_from_slice(&from.data[from_at..from_at + len]);
+            }
+        }
+        return Ok(());
+    }
+
+    let src_layout = Layout::of(src_format).ok_or(Error::PlaneLayout)?;
+    let dst_layout = Layout::of(dst_format).ok_or(Error::PlaneLayout)?;
+    for y in 0..d.height {
+        for x in 0..d.width {
+            let c = pixel::read(&src_layout, src, sx as u32 + x, sy as u32 + y);
+            pixel::write(&dst_layout, dst, d.x as u32 + x, d.y as u32 + y, c);
+        }
+    }
+    Ok(())
+}
+
+impl Bo {
+    /// Copies `src_rect` of `src` to `dst` in this buffer, clipped to
+    /// both buffers.  Differing strides are handled, and so are differing
+    /// formats, by converting every pixel through RGB.  All planes are
+    /// copied; Mesa can only map the first one, so multi-planar buffers
+    /// need minigbm.
+    pub fn copy_region_from(&mut self, src: &Bo, src_rect: Rect,
+                            dst: (i32, i32)) -> Result<(), Error> {
+        let (src_format, dst_format) = (src.get_format().fourcc(), self.get_format().fourcc());
+        let (src_at, d) = match clip((src.get_width(), src.get_height()), src_rect,
+                                     (self.get_width(), self.get_height()), dst) {
+            Some(clipped) => clipped,
+            None => return Ok(()),
+        };
+
+        let src_maps = src.map_planes(Transfer::Read)?;
+        // Read too, so mappings backed by a staging copy keep the pixels
+        // outside the region.
+        let mut dst_maps = self.map_planes(Transfer::ReadWrite)?;
+        let src_planes: Vec<PlaneRef> = src_maps.iter().map(|m| PlaneRef {
+            data: m.as_slice(),
+            stride: m.stride() as usize,
+        }).collect();
+        let mut dst_planes: Vec<PlaneMut> = dst_maps.iter_mut().map(|m| PlaneMut {
+            stride: m.stride() as usize,
+            data: m.as_mut_slice(),
+        }).collect();
+        pixel::check_layout(src_format, src.get_width(), src.get_height(),
+                            src_planes.iter().map(|p| (p.data.len(), p.stride)))?;
+        pixel::check_layout(dst_format, self.get_width(), self.get_height(),
+                            dst_planes.iter().map(|p| (p.data.len(), p.stride)))?;
+        copy_planes(src_format, &src_planes, src_at, dst_format, &mut dst_planes, d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An XRGB8888 image whose pixels hold their own coordinates, with
+    /// `pad` bytes of 0xee after each row.
+    fn xrgb(width: u32, height: u32, pad: usize) -> (Vec<u8>, usize) {
+        let stride = width as usize * 4 + pad;
+        let mut data = vec![0xee; stride * height as usize];
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let at = y * stride + x * 4;
+                data[at..at + 4].copy_from_slice(&[x as u8, y as u8, 0x55, 0x12]);
+            }
+        }
+        (data, stride)
+    }
+
+    fn pixel(data: &[u8], stride: usize, x: usize, y: usize) -> &[u8] {
+        &data[y * stride + x * 4..y * stride + x * 4 + 4]
+    }
+
+    /// Clips and copies between XRGB8888 images, as `copy_region_from`
+    /// does between buffers.
+    fn copy(src: (&[u8], usize, u32, u32), src_rect: Rect,
+            dst: (&mut [u8], usize, u32, u32), at: (i32, i32)) {
+        let (src_at, d) = match clip((src.2, src.3), src_rect, (dst.2, dst.3), at) {
+            Some(c) => c,
+            None => return,
+        };
+        let from = [PlaneRef { data: src.0, stride: src.1 }];
+        let mut to = [PlaneMut { data: dst.0, stride: dst.1 }];
+        copy_planes(FourCC::XRGB8888, &from, src_at, FourCC::XRGB8888, &mut to, d).unwrap();
+    }
+
+    #[test]
+    fn clip_inside() {
+        assert_eq!(clip((10, 10), Rect::new(2, 3, 4, 5), (10, 10), (1, 1)),
+                   Some(((2, 3), Rect::new(1, 1, 4, 5))));
+    }
+
+    #[test]
+    fn clip_negative_source() {
+        // The part left of and above the source is dropped, and the
+        // destination moves along.
+        assert_eq!(clip((10, 10), Rect::new(-2, -3, 5, 5), (10, 10), (4, 4)),
+                   Some(((0, 0), Rect::new(6, 7, 3, 2))));
+    }
+
+    #[test]
+    fn clip_negative_destination() {
+        assert_eq!(clip((10, 10), Rect::new(0, 0, 5, 5), (10, 10), (-3, -1)),
+                   Some(((3, 1), Rect::new(0, 0, 2, 4))));
+    }
+
+    #[test]
+    fn clip_oversized() {
+        assert_eq!(clip((10, 10), Rect::new(0, 0, 100, 100), (4, 6), (0, 0)),
+                   Some(((0, 0), Rect::new(0, 0, 4, 6))));
+        assert_eq!(clip((4, 6), Rect::new(0, 0, 100, 100), (10, 10), (8, 8)),
+                   Some(((0, 0), Rect::new(8, 8, 2, 2))));
+    }
+
+    #[test]
+    fn clip_nothing_left() {
+        assert_eq!(clip((10, 10), Rect::new(10, 0, 5, 5), (10, 10), (0, 0)), None);
+        assert_eq!(clip((10, 10), Rect::new(0, 0, 5, 5), (10, 10), (10, 0)), None);
+        assert_eq!(clip((10, 10), Rect::new(0, 0, 5, 5), (10, 10), (-5, 0)), None);
+        assert_eq!(clip((10, 10), Rect::new(0, 0, 0, 5), (10, 10), (0, 0)), None);
+    }
+
+    #[test]
+    fn copy_with_row_padding() {
+        let (src, src_stride) = xrgb(8, 8, 12);
+        let (mut dst, dst_stride) = (vec![0; 8 * 4 * 8], 8 * 4);
+        copy((&src, src_stride, 8, 8), Rect::new(-2, 1, 6, 3),
+             (&mut dst, dst_stride, 8, 8), (5, 6));
+        // Source (0..4, 1..3) lands at (7.., 6..) clipped to x 7 and y 6..8.
+        assert_eq!(pixel(&dst, dst_stride, 7, 6), &[0, 1, 0x55, 0x12]);
+        assert_eq!(pixel(&dst, dst_stride, 7, 7), &[0, 2, 0x55, 0x12]);
+        assert_eq!(pixel(&dst, dst_stride, 6, 6), &[0, 0, 0, 0]);
+        let copied = dst.chunks(4).filter(|p| p != &[0, 0, 0, 0]).count();
+        assert_eq!(copied, 2);
+    }
+
+    #[test]
+    fn same_format_copies_bytes() {
+        // Unaligned for NV12's chroma but not for XRGB8888, so this takes
+        // the fast path, which keeps the X byte the conversion would zero.
+        let (src, src_stride) = xrgb(4, 4, 0);
+        let (mut dst, dst_stride) = (vec![0; 5 * 4 * 5 + 20], 5 * 4 + 4);
+        copy((&src, src_stride, 4, 4), Rect::new(1, 1, 3, 3),
+             (&mut dst, dst_stride, 5, 5), (1, 1));
+        assert_eq!(pixel(&dst, dst_stride, 1, 1), &[1, 1, 0x55, 0x12]);
+        assert_eq!(pixel(&dst, dst_stride, 3, 3), &[3, 3, 0x55, 0x12]);
+        assert_eq!(pixel(&dst, dst_stride, 0, 0), &[0, 0, 0, 0]);
+        assert_eq!(&dst[dst_stride - 4..dst_stride], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn converting_copy() {
+        // XRGB8888 to XBGR8888 swaps red and blue and writes X as zero.
+        let (src, src_stride) = xrgb(2, 1, 0);
+        let mut dst = vec![0xaa; 8];
+        let from = [PlaneRef { data: &src, stride: src_stride }];
+        let mut to = [PlaneMut { data: &mut dst, stride: 8 }];
+        copy_planes(FourCC::XRGB8888, &from, (0, 0), FourCC::XBGR8888, &mut to,
+                    Rect::new(0, 0, 2, 1)).unwrap();
+        assert_eq!(&dst[..4], &[0x55, 0, 0, 0]);
+        assert_eq!(&dst[4..], &[0x55, 0, 1, 0]);
+    }
+
+    #[test]
+    fn unaligned_yuv_converts() {
+        // An odd x in 4:2:0 can't be copied row by row.
+        assert!(block_aligned(FourCC::NV12, 2, 2, 4));
+        assert!(!block_aligned(FourCC::NV12, 1, 2, 4));
+        assert!(!block_aligned(FourCC::NV12, 2, 1, 4));
+        assert!(!block_aligned(FourCC::YUYV, 0, 0, 3));
+        assert!(block_aligned(FourCC::YUYV, 0, 1, 4));
+        assert!(block_aligned(FourCC::XRGB8888, 1, 1, 3));
+    }
+}
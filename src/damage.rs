@@ -0,0 +1,263 @@
+//! Buffer age and damage tracking for `Surface` buffers.
+//!
+//! Every `lock_front_buffer` ends a frame.  The surface stamps the locked
+//! buffer with the frame number, keyed by its `gbm_bo`, so the next time
+//! the same buffer comes around we know how many frames its previous
+//! contents are behind: its age.  Together with the damage reported for
+//! each frame, that tells what changed since the buffer was last shown,
+//! e.g. for the damage clips of a page flip.
+
+use std::collections::{HashMap, VecDeque};
+
+use {Bo, Surface};
+
+/// Frames of damage kept; buffers older than this count as unknown.
+pub const MAX_DAMAGE_HISTORY: usize = 16;
+
+/// A rectangle in surface coordinates.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x as i64 + self.width as i64).max(other.x as i64 + other.width as i64);
+        let bottom = (self.y as i64 + self.height as i64).max(other.y as i64 + other.height as i64);
+        Rect::new(x, y, (right - x as i64) as u32, (bottom - y as i64) as u32)
+    }
+
+    /// The overlap of both, `None` if they don't overlap.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x as i64 + self.width as i64).min(other.x as i64 + other.width as i64);
+        let bottom = (self.y as i64 + self.height as i64).min(other.y as i64 + other.height as i64);
+        if right <= x as i64 || bottom <= y as i64 {
+            None
+        } else {
+            Some(Rect::new(x, y, (right - x as i64) as u32, (bottom - y as i64) as u32))
+        }
+    }
+}
+
+/// Stamp of a locked surface buffer.
+#[derive(Debug)]
+struct BufferStamp {
+    /// Frame the buffer was last locked in.
+    frame: u64,
+    /// Its age when locked in that frame.
+    age: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct DamageHistory {
+    frame: u64,
+    /// Damage added for the frame being drawn, `None` if none was.
+    pending: Option<Vec<Rect>>,
+    /// Damage of past frames, newest last; `None` for a frame whose
+    /// damage is unknown.
+    frames: VecDeque<Option<Vec<Rect>>>,
+    /// Stamps of the surface's buffers by `gbm_bo` address.  The surface
+    /// owns its buffers, so an address is never reused while it lives.
+    stamps: HashMap<usize, BufferStamp>,
+}
+
+impl DamageHistory {
+    /// Ends the frame `bo` was drawn in and stamps it.
+    pub fn on_lock(&mut self, bo: &Bo) {
+        self.end_frame(bo.ptr as usize)
+    }
+
+    fn end_frame(&mut self, buffer: usize) {
+        self.frame += 1;
+        self.frames.push_back(self.pending.take());
+        while self.frames.len() > MAX_DAMAGE_HISTORY {
+            self.frames.pop_front();
+        }
+        let frame = self.frame;
+        let age = match self.stamps.get(&buffer) {
+            Some(stamp) if frame - stamp.frame <= MAX_DAMAGE_HISTORY as u64 =>
+                (frame - stamp.frame) as u32,
+            _ => 0,
+        };
+        self.stamps.insert(buffer, BufferStamp { frame, age });
+        // Buffers not seen for longer than the history are of unknown
+        // age anyway.
+        self.stamps.retain(|_, stamp| frame - stamp.frame <= MAX_DAMAGE_HISTORY as u64);
+    }
+
+    fn age(&self, buffer: usize) -> u32 {
+        match self.stamps.get(&buffer) {
+            Some(stamp) if stamp.frame == self.frame => stamp.age,
+            _ => 0,
+        }
+    }
+
+    fn damage_since(&self, age: u32) -> Option<Vec<Rect>> {
+        let age = age as usize;
+        if age == 0 || age > self.frames.len() {
+            return None;
+        }
+        let mut rects = Vec::new();
+        for frame in self.frames.iter().skip(self.frames.len() - age) {
+            rects.extend(frame.as_ref()?.iter().filter(|r| !r.is_empty()));
+        }
+        Some(rects)
+    }
+}
+
+impl Surface {
+    /// Reports damage for the frame being drawn, to be called before
+    /// `lock_front_buffer`.  A frame without any reported damage counts
+    /// as fully damaged.
+    pub fn add_damage(&mut self, rects: &[Rect]) {
+        self.damage.pending.get_or_insert_with(Vec::new).extend_from_slice(rects);
+    }
+
+    /// Number of frames locked so far.
+    pub fn frame_count(&self) -> u64 {
+        self.damage.frame
+    }
+
+    /// Age of the buffer `lock_front_buffer` just returned: how many
+    /// frames old its contents were before the frame that ended with that
+    /// call was drawn into it, or 0 if unknown, as for a buffer used for
+    /// the first time.
+    ///
+    /// The age is only known once the frame is over, so this answers for
+    /// the most recently locked buffer alone and only until the next
+    /// `lock_front_buffer`; it is 0 for any other buffer.  It suits the
+    /// damage clips of a page flip, see `buffer_damage`.  To redraw only
+    /// part of the next frame, query the age of the buffer about to be
+    /// drawn from EGL (`EGL_EXT_buffer_age`) instead.
+    pub fn buffer_age(&self, bo: &Bo) -> u32 {
+        self.damage.age(bo.ptr as usize)
+    }
+
+    /// Everything damaged in the last `age` frames, i.e. what a buffer of
+    /// that age lacks.  `None` means everything must be assumed damaged.
+    pub fn damage_since(&self, age: u32) -> Option<Vec<Rect>> {
+        self.damage.damage_since(age)
+    }
+
+    /// `damage_since` the age of `bo`: what changed in it since it was
+    /// last locked.  Like `buffer_age`, only for the buffer just locked.
+    pub fn buffer_damage(&self, bo: &Bo) -> Option<Vec<Rect>> {
+        self.damage_since(self.buffer_age(bo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn union_with_empty() {
+        let r = Rect::new(1, 2, 3, 4);
+        assert_eq!(r.union(&Rect::new(50, 50, 0, 10)), r);
+        assert_eq!(Rect::default().union(&r), r);
+        assert!(Rect::default().union(&Rect::new(9, 9, 5, 0)).is_empty());
+    }
+
+    #[test]
+    fn union_overlapping() {
+        assert_eq!(Rect::new(0, 0, 4, 4).union(&Rect::new(2, -1, 4, 2)),
+                   Rect::new(0, -1, 6, 5));
+        assert_eq!(Rect::new(0, 0, 2, 2).union(&Rect::new(5, 5, 1, 1)),
+                   Rect::new(0, 0, 6, 6));
+    }
+
+    #[test]
+    fn intersection() {
+        assert_eq!(Rect::new(0, 0, 4, 4).intersection(&Rect::new(2, -1, 4, 2)),
+                   Some(Rect::new(2, 0, 2, 1)));
+        assert_eq!(Rect::new(0, 0, 4, 4).intersection(&Rect::new(1, 1, 2, 2)),
+                   Some(Rect::new(1, 1, 2, 2)));
+        // Touching edges don't overlap.
+        assert_eq!(Rect::new(0, 0, 4, 4).intersection(&Rect::new(4, 0, 4, 4)), None);
+        assert_eq!(Rect::new(0, 0, 4, 4).intersection(&Rect::new(1, 1, 0, 2)), None);
+        assert_eq!(Rect::default().intersection(&Rect::default()), None);
+    }
+
+    #[test]
+    fn age_after_frames() {
+        let mut h = DamageHistory::default();
+        // Triple buffering: buffers 1, 2, 3 in turn.
+        for frame in 0..10 {
+            let buffer = frame % 3 + 1;
+            h.pending = Some(vec![Rect::new(frame as i32, 0, 1, 1)]);
+            h.end_frame(buffer);
+            assert_eq!(h.age(buffer), if frame < 3 { 0 } else { 3 });
+            // Only the buffer just locked has an age.
+            assert_eq!(h.age(frame % 3 + 2), 0);
+        }
+        assert_eq!(h.frame, 10);
+        assert_eq!(h.damage_since(3), Some(vec![
+            Rect::new(7, 0, 1, 1), Rect::new(8, 0, 1, 1), Rect::new(9, 0, 1, 1),
+        ]));
+    }
+
+    #[test]
+    fn unknown_damage() {
+        let mut h = DamageHistory {
+            pending: Some(vec![Rect::new(0, 0, 1, 1)]),
+            ..DamageHistory::default()
+        };
+        h.end_frame(1);
+        h.end_frame(2);
+        h.pending = Some(vec![Rect::new(2, 0, 1, 1)]);
+        h.end_frame(1);
+        assert_eq!(h.damage_since(1), Some(vec![Rect::new(2, 0, 1, 1)]));
+        // The middle frame reported nothing, so counts as fully damaged.
+        assert_eq!(h.damage_since(2), None);
+        assert_eq!(h.damage_since(0), None);
+        assert_eq!(h.damage_since(4), None);
+    }
+
+    #[test]
+    fn eviction() {
+        let mut h = DamageHistory::default();
+        h.end_frame(1);
+        for _ in 0..MAX_DAMAGE_HISTORY - 1 {
+            h.pending = Some(Vec::new());
+            h.end_frame(2);
+        }
+        h.end_frame(1);
+        assert_eq!(h.age(1), MAX_DAMAGE_HISTORY as u32);
+        assert_eq!(h.frames.len(), MAX_DAMAGE_HISTORY);
+
+        // A buffer left out for more frames than the history holds is
+        // forgotten.
+        for _ in 0..MAX_DAMAGE_HISTORY + 1 {
+            h.pending = Some(Vec::new());
+            h.end_frame(2);
+        }
+        assert_eq!(h.frames.len(), MAX_DAMAGE_HISTORY);
+        assert!(!h.stamps.contains_key(&1));
+        h.end_frame(1);
+        assert_eq!(h.age(1), 0);
+        assert_eq!(h.damage_since(MAX_DAMAGE_HISTORY as u32 + 1), None);
+    }
+}
@@ -0,0 +1,279 @@
+//! Buffers described as dmabufs, for passing them to other APIs and
+//! processes.
+
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::slice;
+
+use libc::{self, c_void};
+
+use {ffi, Bo, BoFlags, BoImport, Device, Error, FourCC, Transfer};
+
+#[derive(Debug)]
+pub struct DmabufPlane {
+    pub fd: OwnedFd,
+    /// Byte offset of the plane within `fd`.
+    pub offset: u32,
+    pub stride: u32,
+}
+
+/// A buffer as a set of dmabuf fds plus the layout needed to interpret
+/// them.  The fds are owned and closed when the `Dmabuf` is dropped.
+#[derive(Debug)]
+pub struct Dmabuf {
+    pub width: u32,
+    pub height: u32,
+    pub format: FourCC,
+    /// `ffi::DRM_FORMAT_MOD_INVALID` when the layout is implicit.
+    pub modifier: u64,
+    pub planes: Vec<DmabufPlane>,
+}
+
+impl DmabufPlane {
+    /// Maps the whole dmabuf this plane lives in; the plane itself
+    /// starts `offset` bytes in.
+    pub fn mmap(&self, transfer: Transfer) -> io::Result<DmabufMmap<'_>> {
+        DmabufMmap::new(self.fd.as_fd(), transfer)
+    }
+}
+
+impl Dmabuf {
+    /// Duplicates every fd.
+    pub fn try_clone(&self) -> io::Result<Dmabuf> {
+        let mut planes = Vec::with_capacity(self.planes.len());
+        for p in &self.planes {
+            planes.push(DmabufPlane {
+                fd: p.fd.try_clone()?,
+                offset: p.offset,
+                stride: p.stride,
+            });
+        }
+        Ok(Dmabuf {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            modifier: self.modifier,
+            planes,
+        })
+    }
+
+    fn as_import_data(&self) -> Option<ffi::gbm_import_fd_modifier_data> {
+        if self.planes.is_empty() || self.planes.len() > ffi::GBM_MAX_PLANES {
+            return None;
+        }
+        let mut data = ffi::gbm_import_fd_modifier_data {
+            width: self.width,
+            height: self.height,
+            format: self.format as u32,
+            num_fds: self.planes.len() as u32,
+            fds: [-1; ffi::GBM_MAX_PLANES],
+            strides: [0; ffi::GBM_MAX_PLANES],
+            offsets: [0; ffi::GBM_MAX_PLANES],
+            modifier: self.modifier,
+        };
+        for (i, p) in self.planes.iter().enumerate() {
+            data.fds[i] = p.fd.as_raw_fd();
+            data.strides[i] = p.stride as i32;
+            data.offsets[i] = p.offset as i32;
+        }
+        Some(data)
+    }
+}
+
+impl Bo {
+    /// Exports every plane as a new dmabuf fd.
+    pub fn export(&self) -> Result<Dmabuf, Error> {
+        let format = self.get_format().fourcc();
+        let mut planes = Vec::new();
+        for plane in 0..self.plane_count() {
+            let fd = self.get_fd_for_plane(plane);
+            if fd < 0 {
+                return Err(Error::BoExport);
+            }
+            planes.push(DmabufPlane {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                offset: self.get_offset(plane),
+                stride: self.get_stride_for_plane(plane),
+            });
+        }
+        Ok(Dmabuf {
+            width: self.get_width(),
+            height: self.get_height(),
+            format,
+            modifier: self.get_modifier(),
+            planes,
+        })
+    }
+}
+
+impl Device {
+    /// Imports `dmabuf`.  libgbm duplicates what it needs, so the
+    /// `Dmabuf` can be dropped afterwards.
+    pub fn import_dmabuf(&self, dmabuf: &Dmabuf, usage: BoFlags) -> Result<Bo, Error> {
+        self.bo_import(BoImport::Dmabuf(dmabuf), usage).map_err(|_| Error::BoImport)
+    }
+}
+
+/// Called by `Device::bo_import` with the device lock held.
+pub unsafe fn import(dev: *mut ffi::gbm_device, dmabuf: &Dmabuf,
+                     usage: BoFlags) -> *mut ffi::gbm_bo {
+    match dmabuf.as_import_data() {
+        None => ptr::null_mut(),
+        Some(mut data) => {
+            ffi::gbm_bo_import(dev, ffi::GBM_BO_IMPORT_FD_MODIFIER,
+                               &mut data as *mut _ as *mut _, usage.bits())
+        }
+    }
+}
+
+/// A CPU mapping of a dmabuf fd, unmapped on drop.
+///
+/// The memory may only be touched between `DMA_BUF_IOCTL_SYNC` start and
+/// end calls, which `begin` and the returned `CpuAccess` take care of.
+/// Map once and `begin` for every access; mapping is the expensive part.
+#[derive(Debug)]
+pub struct DmabufMmap<'a> {
+    fd: BorrowedFd<'a>,
+    addr: *mut u8,
+    len: usize,
+    transfer: Transfer,
+}
+
+/// Access to a `DmabufMmap` inside a sync bracket, ended on drop.
+#[derive(Debug)]
+pub struct CpuAccess<'m, 'a: 'm> {
+    map: &'m mut DmabufMmap<'a>,
+}
+
+pub fn sync_flags(transfer: Transfer) -> u64 {
+    match transfer {
+        Transfer::Read => ffi::DMA_BUF_SYNC_READ,
+        Transfer::Write => ffi::DMA_BUF_SYNC_WRITE,
+        Transfer::ReadWrite => ffi::DMA_BUF_SYNC_RW,
+    }
+}
+
+/// Issues `DMA_BUF_IOCTL_SYNC`, retrying when interrupted as the kernel
+/// asks us to.
+fn dma_buf_sync(fd: BorrowedFd, flags: u64) -> io::Result<()> {
+    let sync = ffi::dma_buf_sync { flags };
+    loop {
+        let ret = unsafe {
+            libc::ioctl(fd.as_raw_fd(), ffi::DMA_BUF_IOCTL_SYNC as _, &sync)
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+impl<'a> DmabufMmap<'a> {
+    /// Maps all of `fd`, whose size is found by seeking to its end.
+    pub fn new(fd: BorrowedFd<'a>, transfer: Transfer) -> io::Result<DmabufMmap<'a>> {
+        let len = unsafe { libc::lseek(fd.as_raw_fd(), 0, libc::SEEK_END) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let prot = match transfer {
+            Transfer::Read => libc::PROT_READ,
+            Transfer::Write | Transfer::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+        };
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(), len as usize, prot, libc::MAP_SHARED,
+                       fd.as_raw_fd(), 0)
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DmabufMmap {
+            fd,
+            addr: addr as *mut u8,
+            len: len as usize,
+            transfer,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn transfer(&self) -> Transfer {
+        self.transfer
+    }
+
+    /// Starts CPU access in the direction the buffer was mapped for.
+    pub fn begin(&mut self) -> io::Result<CpuAccess<'_, 'a>> {
+        dma_buf_sync(self.fd, ffi::DMA_BUF_SYNC_START | sync_flags(self.transfer))?;
+        Ok(CpuAccess { map: self })
+    }
+}
+
+impl<'a> Drop for DmabufMmap<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr as *mut c_void, self.len) };
+    }
+}
+
+impl<'m, 'a> CpuAccess<'m, 'a> {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map.addr, self.map.len) }
+    }
+
+    /// Panics if the buffer was mapped read only.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        assert!(self.map.transfer.writable(), "dmabuf is mapped read only");
+        unsafe { slice::from_raw_parts_mut(self.map.addr, self.map.len) }
+    }
+
+    /// Ends the access, reporting errors that dropping would ignore.
+    pub fn end(self) -> io::Result<()> {
+        let res = dma_buf_sync(self.map.fd, ffi::DMA_BUF_SYNC_END | sync_flags(self.map.transfer));
+        ::std::mem::forget(self);
+        res
+    }
+}
+
+impl<'m, 'a> Drop for CpuAccess<'m, 'a> {
+    fn drop(&mut self) {
+        let _ = dma_buf_sync(self.map.fd, ffi::DMA_BUF_SYNC_END | sync_flags(self.map.transfer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsFd;
+
+    use super::*;
+    use udmabuf::UdmabufAllocator;
+    use Transfer;
+
+    #[test]
+    #[ignore = "needs access to /dev/udmabuf"]
+    fn cpu_access_writes_reach_memory() {
+        let alloc = UdmabufAllocator::new().expect("/dev/udmabuf");
+        let dmabuf = alloc.allocate_bytes(1).unwrap();
+        let mut map = DmabufMmap::new(dmabuf.as_fd(), Transfer::ReadWrite).unwrap();
+        assert!(!map.is_empty());
+        {
+            let mut access = map.begin().unwrap();
+            for (i, b) in access.as_mut_slice().iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            access.end().unwrap();
+        }
+
+        // A second mapping sees the same memory.
+        let mut other = DmabufMmap::new(dmabuf.as_fd(), Transfer::Read).unwrap();
+        assert_eq!(other.len(), map.len());
+        let access = other.begin().unwrap();
+        assert!(access.as_slice().iter().enumerate().all(|(i, &b)| b == i as u8));
+    }
+}
@@ -0,0 +1,248 @@
+//! Attribute lists for importing dmabufs as EGLImages
+//! (`EGL_EXT_image_dma_buf_import` and `..._modifiers`).
+
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::ptr;
+
+use egl::{self, EGLContext, EGLDisplay, EGLenum, EGLint};
+use libc::c_void;
+
+use super::{proc_address, EglError, EglImage};
+use {ffi, Bo, Dmabuf, EGLImageKHR, FourCC};
+
+const EGL_WIDTH: EGLint = 0x3057;
+const EGL_HEIGHT: EGLint = 0x3056;
+const EGL_LINUX_DMA_BUF_EXT: EGLenum = 0x3270;
+const EGL_LINUX_DRM_FOURCC_EXT: EGLint = 0x3271;
+const EGL_YUV_COLOR_SPACE_HINT_EXT: EGLint = 0x327B;
+const EGL_SAMPLE_RANGE_HINT_EXT: EGLint = 0x327C;
+const EGL_YUV_CHROMA_HORIZONTAL_SITING_HINT_EXT: EGLint = 0x327D;
+const EGL_YUV_CHROMA_VERTICAL_SITING_HINT_EXT: EGLint = 0x327E;
+
+/// `(fd, offset, pitch, modifier lo, modifier hi)` attribute names of
+/// each plane.
+const PLANE_ATTRIBS: [[EGLint; 5]; 4] = [
+    [0x3272, 0x3273, 0x3274, 0x3443, 0x3444],
+    [0x3275, 0x3276, 0x3277, 0x3445, 0x3446],
+    [0x3278, 0x3279, 0x327A, 0x3447, 0x3448],
+    [0x3440, 0x3441, 0x3442, 0x3449, 0x344A],
+];
+
+type CreateImageKhr = unsafe extern "C" fn(EGLDisplay, EGLContext, EGLenum,
+                                           *mut c_void, *const EGLint) -> EGLImageKHR;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum YuvColorSpace {
+    Rec601,
+    Rec709,
+    Rec2020,
+}
+impl YuvColorSpace {
+    fn as_egl(&self) -> EGLint {
+        match *self {
+            YuvColorSpace::Rec601 => 0x327F,
+            YuvColorSpace::Rec709 => 0x3280,
+            YuvColorSpace::Rec2020 => 0x3281,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SampleRange {
+    Full,
+    Narrow,
+}
+impl SampleRange {
+    fn as_egl(&self) -> EGLint {
+        match *self {
+            SampleRange::Full => 0x3282,
+            SampleRange::Narrow => 0x3283,
+        }
+    }
+}
+
+/// Position of chroma samples relative to luma samples.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ChromaSiting {
+    /// Co-sited with the first luma sample.
+    Zero,
+    /// Half way between luma samples.
+    Half,
+}
+impl ChromaSiting {
+    fn as_egl(&self) -> EGLint {
+        match *self {
+            ChromaSiting::Zero => 0x3284,
+            ChromaSiting::Half => 0x3285,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PlaneFd<'a> {
+    Borrowed(BorrowedFd<'a>),
+    /// Exported by `from_bo`.
+    Owned(OwnedFd),
+}
+
+impl<'a> AsRawFd for PlaneFd<'a> {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            PlaneFd::Borrowed(ref fd) => fd.as_raw_fd(),
+            PlaneFd::Owned(ref fd) => fd.as_raw_fd(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Plane<'a> {
+    fd: PlaneFd<'a>,
+    offset: u32,
+    pitch: u32,
+}
+
+/// Builds the attribute list for `eglCreateImageKHR(EGL_LINUX_DMA_BUF_EXT)`.
+///
+/// The dmabuf exported by `from_bo` is owned by the builder and closed
+/// when it is dropped; fds passed to `plane` or `from_dmabuf` are
+/// borrowed for `'a`.  EGL duplicates the fds it needs, so the builder
+/// can be dropped once the image exists.
+#[derive(Debug)]
+pub struct DmabufImageBuilder<'a> {
+    width: u32,
+    height: u32,
+    fourcc: FourCC,
+    modifier: Option<u64>,
+    planes: Vec<Plane<'a>>,
+    color_space: Option<YuvColorSpace>,
+    sample_range: Option<SampleRange>,
+    siting: Option<(ChromaSiting, ChromaSiting)>,
+}
+
+impl<'a> DmabufImageBuilder<'a> {
+    pub fn new(width: u32, height: u32, fourcc: FourCC) -> DmabufImageBuilder<'a> {
+        DmabufImageBuilder {
+            width,
+            height,
+            fourcc,
+            modifier: None,
+            planes: Vec::new(),
+            color_space: None,
+            sample_range: None,
+            siting: None,
+        }
+    }
+
+    /// Describes every plane of `bo`, exporting one fd per plane.
+    pub fn from_bo(bo: &Bo) -> Result<DmabufImageBuilder<'static>, EglError> {
+        let dmabuf = bo.export().map_err(|_| EglError::ExportFd)?;
+        let mut builder = DmabufImageBuilder::new(dmabuf.width, dmabuf.height,
+                                                  dmabuf.format);
+        builder.modifier(dmabuf.modifier);
+        builder.planes = dmabuf.planes.into_iter().map(|p| Plane {
+            fd: PlaneFd::Owned(p.fd),
+            offset: p.offset,
+            pitch: p.stride,
+        }).collect();
+        Ok(builder)
+    }
+
+    /// Describes `dmabuf`, borrowing its fds.
+    pub fn from_dmabuf(dmabuf: &'a Dmabuf) -> DmabufImageBuilder<'a> {
+        let mut builder = DmabufImageBuilder::new(dmabuf.width, dmabuf.height,
+                                                  dmabuf.format);
+        for p in &dmabuf.planes {
+            builder.plane(p.fd.as_fd(), p.offset, p.stride);
+        }
+        builder.modifier(dmabuf.modifier);
+        builder
+    }
+
+    /// Adds the next plane.
+    pub fn plane(&mut self, fd: BorrowedFd<'a>, offset: u32,
+                 pitch: u32) -> &mut DmabufImageBuilder<'a> {
+        self.planes.push(Plane { fd: PlaneFd::Borrowed(fd), offset, pitch });
+        self
+    }
+    /// Modifier of every plane.  `DRM_FORMAT_MOD_INVALID` leaves it out
+    /// and lets the driver assume an implicit layout.
+    pub fn modifier(&mut self, modifier: u64) -> &mut DmabufImageBuilder<'a> {
+        self.modifier = if modifier == ffi::DRM_FORMAT_MOD_INVALID {
+            None
+        } else {
+            Some(modifier)
+        };
+        self
+    }
+    pub fn color_space(&mut self, color_space: YuvColorSpace) -> &mut DmabufImageBuilder<'a> {
+        self.color_space = Some(color_space);
+        self
+    }
+    pub fn sample_range(&mut self, range: SampleRange) -> &mut DmabufImageBuilder<'a> {
+        self.sample_range = Some(range);
+        self
+    }
+    pub fn chroma_siting(&mut self, horizontal: ChromaSiting,
+                         vertical: ChromaSiting) -> &mut DmabufImageBuilder<'a> {
+        self.siting = Some((horizontal, vertical));
+        self
+    }
+
+    /// The complete attribute list, terminated by `EGL_NONE`.
+    pub fn attributes(&self) -> Result<Vec<EGLint>, EglError> {
+        if self.planes.len() > PLANE_ATTRIBS.len() {
+            return Err(EglError::TooManyPlanes(self.planes.len()));
+        }
+        let mut attribs = vec![
+            EGL_WIDTH, self.width as EGLint,
+            EGL_HEIGHT, self.height as EGLint,
+            EGL_LINUX_DRM_FOURCC_EXT, self.fourcc as u32 as EGLint,
+        ];
+        for (plane, names) in self.planes.iter().zip(PLANE_ATTRIBS.iter()) {
+            attribs.extend_from_slice(&[
+                names[0], plane.fd.as_raw_fd(),
+                names[1], plane.offset as EGLint,
+                names[2], plane.pitch as EGLint,
+            ]);
+            if let Some(modifier) = self.modifier {
+                attribs.extend_from_slice(&[
+                    names[3], (modifier & 0xffff_ffff) as u32 as EGLint,
+                    names[4], (modifier >> 32) as u32 as EGLint,
+                ]);
+            }
+        }
+        if let Some(cs) = self.color_space {
+            attribs.extend_from_slice(&[EGL_YUV_COLOR_SPACE_HINT_EXT, cs.as_egl()]);
+        }
+        if let Some(range) = self.sample_range {
+            attribs.extend_from_slice(&[EGL_SAMPLE_RANGE_HINT_EXT, range.as_egl()]);
+        }
+        if let Some((h, v)) = self.siting {
+            attribs.extend_from_slice(&[
+                EGL_YUV_CHROMA_HORIZONTAL_SITING_HINT_EXT, h.as_egl(),
+                EGL_YUV_CHROMA_VERTICAL_SITING_HINT_EXT, v.as_egl(),
+            ]);
+        }
+        attribs.push(egl::EGL_NONE);
+        Ok(attribs)
+    }
+
+    /// Creates the image on `display`.
+    ///
+    /// # Safety
+    ///
+    /// `display` must be a valid, initialized EGL display.
+    pub unsafe fn create_image(&self, display: EGLDisplay) -> Result<EglImage, EglError> {
+        let attribs = self.attributes()?;
+        let create: CreateImageKhr = unsafe { proc_address("eglCreateImageKHR\0")? };
+        let image = unsafe {
+            create(display, egl::EGL_NO_CONTEXT, EGL_LINUX_DMA_BUF_EXT,
+                   ptr::null_mut(), attribs.as_ptr())
+        };
+        if image.is_null() {
+            Err(EglError::CreateImage(egl::get_error()))
+        } else {
+            Ok(unsafe { EglImage::from_raw(display, image) })
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Owned EGLImages.
+
+use std::mem::forget;
+
+use egl::{EGLBoolean, EGLDisplay};
+
+use super::proc_address;
+use EGLImageKHR;
+
+type DestroyImageKhr = unsafe extern "C" fn(EGLDisplay, EGLImageKHR) -> EGLBoolean;
+
+/// An `EGLImageKHR` together with the display it belongs to, destroyed
+/// with `eglDestroyImageKHR` on drop.
+///
+/// The display must stay initialized for as long as the image lives.
+#[derive(Debug)]
+pub struct EglImage {
+    display: EGLDisplay,
+    image: EGLImageKHR,
+}
+
+impl EglImage {
+    /// Takes ownership of `image`.
+    ///
+    /// # Safety
+    ///
+    /// `image` must have been created on `display` and not be destroyed
+    /// elsewhere.
+    pub unsafe fn from_raw(display: EGLDisplay, image: EGLImageKHR) -> EglImage {
+        EglImage { display, image }
+    }
+
+    pub fn as_raw(&self) -> EGLImageKHR {
+        self.image
+    }
+
+    pub fn display(&self) -> EGLDisplay {
+        self.display
+    }
+
+    /// Gives up ownership without destroying the image.
+    pub fn into_raw(self) -> EGLImageKHR {
+        let image = self.image;
+        forget(self);
+        image
+    }
+}
+
+impl Drop for EglImage {
+    fn drop(&mut self) {
+        // The lookup only fails if the image could never have been
+        // created in the first place.
+        if let Ok(destroy) = unsafe { proc_address::<DestroyImageKhr>("eglDestroyImageKHR\0") } {
+            unsafe { destroy(self.display, self.image) };
+        }
+    }
+}
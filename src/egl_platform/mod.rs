@@ -0,0 +1,234 @@
+//! Creating EGL displays and window surfaces on top of GBM.
+//!
+//! These wrap `EGL_KHR_platform_gbm` (or its `EGL_MESA_platform_gbm`
+//! predecessor): a display is created from a `Device`, a config is
+//! picked whose native visual matches a `Surface`'s format, and a
+//! window surface is created for the `Surface`.
+
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::mem;
+use std::ptr;
+
+use egl::{self, EGLConfig, EGLDisplay, EGLSurface, EGLenum, EGLint};
+use libc::{c_char, c_void};
+
+use {Device, FourCC, Surface};
+
+mod dmabuf;
+mod image;
+
+pub use self::image::EglImage;
+pub use self::dmabuf::{ChromaSiting, DmabufImageBuilder, SampleRange, YuvColorSpace};
+
+pub const EGL_PLATFORM_GBM_KHR: EGLenum = 0x31D7;
+
+/// Client extensions providing `EGL_PLATFORM_GBM_KHR`.
+const PLATFORM_EXTENSIONS: &[&str] =
+    &["EGL_KHR_platform_gbm", "EGL_MESA_platform_gbm"];
+
+type GetPlatformDisplayExt =
+    unsafe extern "C" fn(EGLenum, *mut c_void, *const EGLint) -> EGLDisplay;
+type CreatePlatformWindowSurfaceExt =
+    unsafe extern "C" fn(EGLDisplay, EGLConfig, *mut c_void, *const EGLint) -> EGLSurface;
+
+mod sys {
+    use egl::{EGLBoolean, EGLConfig, EGLDisplay, EGLint};
+    use libc::{c_char, c_void};
+
+    #[link(name = "EGL")]
+    extern "C" {
+        pub fn eglGetProcAddress(procname: *const c_char) -> *mut c_void;
+        pub fn eglChooseConfig(dpy: EGLDisplay, attrib_list: *const EGLint,
+                               configs: *mut EGLConfig, config_size: EGLint,
+                               num_config: *mut EGLint) -> EGLBoolean;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EglError {
+    /// None of the client extensions for the GBM platform is present.
+    MissingExtension,
+    /// `eglGetProcAddress` returned nothing for the named function.
+    MissingFunction(&'static str),
+    /// `eglGetPlatformDisplayEXT` failed with the given EGL error.
+    GetDisplay(EGLint),
+    Initialize(EGLint),
+    ChooseConfig(EGLint),
+    /// No config has a native visual id equal to the fourcc.
+    NoMatchingConfig(FourCC),
+    CreateSurface(EGLint),
+    /// Exporting a dmabuf fd from a `Bo` failed.
+    ExportFd,
+    /// More planes than `EGL_EXT_image_dma_buf_import` can describe.
+    TooManyPlanes(usize),
+    CreateImage(EGLint),
+}
+
+impl fmt::Display for EglError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EglError::MissingFunction(name) => write!(f, "EGL does not provide {}", name),
+            EglError::NoMatchingConfig(fcc) => {
+                write!(f, "no EGL config with native visual {}", fcc.name())
+            }
+            EglError::GetDisplay(e) | EglError::Initialize(e) |
+            EglError::ChooseConfig(e) | EglError::CreateSurface(e) |
+            EglError::CreateImage(e) => {
+                write!(f, "{} (EGL error 0x{:04x})", self.message(), e)
+            }
+            EglError::TooManyPlanes(n) => write!(f, "{} planes, at most 4 supported", n),
+            EglError::MissingExtension | EglError::ExportFd => {
+                write!(f, "{}", self.message())
+            }
+        }
+    }
+}
+
+impl EglError {
+    fn message(&self) -> &'static str {
+        match *self {
+            EglError::MissingExtension => "EGL lacks the GBM platform extension",
+            EglError::MissingFunction(_) => "EGL function missing",
+            EglError::GetDisplay(_) => "Failed to get EGL display",
+            EglError::Initialize(_) => "Failed to initialize EGL display",
+            EglError::ChooseConfig(_) => "Failed to choose EGL config",
+            EglError::NoMatchingConfig(_) => "No EGL config matches the surface format",
+            EglError::CreateSurface(_) => "Failed to create EGL window surface",
+            EglError::ExportFd => "Failed to export dmabuf fd from gbm_bo",
+            EglError::TooManyPlanes(_) => "Too many planes for an EGL dmabuf import",
+            EglError::CreateImage(_) => "Failed to create EGLImage",
+        }
+    }
+}
+
+impl error::Error for EglError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+/// Looks up an extension function.  `T` must be the function's pointer
+/// type.
+unsafe fn proc_address<T: Copy>(name: &'static str) -> Result<T, EglError> {
+    debug_assert!(name.ends_with('\0'));
+    let ptr = sys::eglGetProcAddress(name.as_ptr() as *const c_char);
+    if ptr.is_null() {
+        Err(EglError::MissingFunction(name.trim_end_matches('\0')))
+    } else {
+        Ok(mem::transmute_copy(&ptr))
+    }
+}
+
+fn has_client_extension() -> bool {
+    // Client extensions are queried on EGL_NO_DISPLAY.
+    egl::query_string(egl::EGL_NO_DISPLAY, egl::EGL_EXTENSIONS)
+        .map(CStr::to_string_lossy)
+        .is_some_and(|exts| exts.split(' ').any(|e| PLATFORM_EXTENSIONS.contains(&e)))
+}
+
+impl Device {
+    /// Creates and initializes an EGL display for this device with
+    /// `eglGetPlatformDisplayEXT(EGL_PLATFORM_GBM_KHR, ...)`.
+    ///
+    /// The display is not owned: EGL hands out the same display for the
+    /// same device, and `egl::terminate` must be called on it by the
+    /// user once every context and surface is gone.
+    pub fn egl_display(&self) -> Result<EGLDisplay, EglError> {
+        if !has_client_extension() {
+            return Err(EglError::MissingExtension);
+        }
+        let get_display: GetPlatformDisplayExt = unsafe {
+            proc_address("eglGetPlatformDisplayEXT\0")?
+        };
+        let display = unsafe {
+            get_display(EGL_PLATFORM_GBM_KHR, self.inner.ptr as *mut c_void, ptr::null())
+        };
+        if display == egl::EGL_NO_DISPLAY {
+            return Err(EglError::GetDisplay(egl::get_error()));
+        }
+        let (mut major, mut minor) = (0, 0);
+        if !egl::initialize(display, &mut major, &mut minor) {
+            return Err(EglError::Initialize(egl::get_error()));
+        }
+        Ok(display)
+    }
+}
+
+/// Picks the first config matching `attribs` whose
+/// `EGL_NATIVE_VISUAL_ID` is the fourcc of `surface`'s format.
+///
+/// `attribs` need not be terminated by `EGL_NONE`.  `EGL_SURFACE_TYPE`
+/// defaults to `EGL_WINDOW_BIT` if not given.
+///
+/// # Safety
+///
+/// `display` must be a valid, initialized EGL display.
+pub unsafe fn choose_config(display: EGLDisplay, attribs: &[EGLint],
+                            surface: &Surface) -> Result<EGLConfig, EglError> {
+    let mut list: Vec<EGLint> = attribs.to_vec();
+    if list.last() == Some(&egl::EGL_NONE) {
+        list.pop();
+    }
+    if !list.chunks(2).any(|kv| kv[0] == egl::EGL_SURFACE_TYPE) {
+        list.push(egl::EGL_SURFACE_TYPE);
+        list.push(egl::EGL_WINDOW_BIT);
+    }
+    list.push(egl::EGL_NONE);
+
+    let mut count = 0;
+    let ok = unsafe {
+        sys::eglChooseConfig(display, list.as_ptr(), ptr::null_mut(), 0, &mut count)
+    };
+    if ok != egl::EGL_TRUE {
+        return Err(EglError::ChooseConfig(egl::get_error()));
+    }
+    let mut configs = vec![ptr::null_mut(); count as usize];
+    let ok = unsafe {
+        sys::eglChooseConfig(display, list.as_ptr(), configs.as_mut_ptr(),
+                             count, &mut count)
+    };
+    if ok != egl::EGL_TRUE {
+        return Err(EglError::ChooseConfig(egl::get_error()));
+    }
+    configs.truncate(count as usize);
+
+    let fourcc = surface.get_format().fourcc();
+    configs.into_iter()
+        .find(|&config| {
+            let mut id = 0;
+            egl::get_config_attrib(display, config, egl::EGL_NATIVE_VISUAL_ID, &mut id)
+                && id as u32 == fourcc as u32
+        })
+        .ok_or(EglError::NoMatchingConfig(fourcc))
+}
+
+impl Surface {
+    /// Creates an EGL window surface rendering into this surface with
+    /// `eglCreatePlatformWindowSurfaceEXT`.  `config` should come from
+    /// `choose_config` with this surface.
+    ///
+    /// # Safety
+    ///
+    /// `display` must be a valid, initialized EGL display and `config` one
+    /// of its configs.
+    pub unsafe fn create_egl_surface(&self, display: EGLDisplay, config: EGLConfig,
+                                     attribs: &[EGLint]) -> Result<EGLSurface, EglError> {
+        let create: CreatePlatformWindowSurfaceExt = unsafe {
+            proc_address("eglCreatePlatformWindowSurfaceEXT\0")?
+        };
+        let mut list: Vec<EGLint> = attribs.to_vec();
+        if list.last() != Some(&egl::EGL_NONE) {
+            list.push(egl::EGL_NONE);
+        }
+        let surface = unsafe {
+            create(display, config, self.ptr as *mut c_void, list.as_ptr())
+        };
+        if surface == egl::EGL_NO_SURFACE {
+            Err(EglError::CreateSurface(egl::get_error()))
+        } else {
+            Ok(surface)
+        }
+    }
+}
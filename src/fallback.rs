@@ -0,0 +1,148 @@
+//! Allocating from an ordered list of acceptable layouts.
+//!
+//! Scanout allocations in particular tend to fail with one modifier set
+//! and succeed with another, only in LINEAR, or only without alpha.
+//! `Device::allocate_with_fallback` tries each candidate in turn and
+//! records why the earlier ones were passed over.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use {Bo, BoFlags, BoFormat, Device, FourCC};
+
+/// One way of allocating a buffer.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AllocCandidate {
+    pub format: FourCC,
+    /// Acceptable modifiers, or empty to let the driver pick without
+    /// explicit modifiers.
+    #[cfg_attr(feature = "serde", serde(with = "::serde_impls::modifiers"))]
+    pub modifiers: Vec<u64>,
+    pub flags: BoFlags,
+}
+
+/// A buffer size and the candidates for it, most preferred first.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AllocRequest {
+    pub width: u32,
+    pub height: u32,
+    pub candidates: Vec<AllocCandidate>,
+}
+
+impl AllocRequest {
+    pub fn new(width: u32, height: u32) -> AllocRequest {
+        AllocRequest { width, height, candidates: Vec::new() }
+    }
+
+    /// Appends a candidate.
+    pub fn candidate(mut self, format: FourCC, modifiers: &[u64], flags: BoFlags) -> AllocRequest {
+        self.candidates.push(AllocCandidate {
+            format,
+            modifiers: modifiers.to_vec(),
+            flags,
+        });
+        self
+    }
+}
+
+/// Why a candidate was passed over.
+#[derive(Debug)]
+pub enum AllocFailure {
+    /// `is_format_supported` rejected the format and flags.
+    FormatUnsupported,
+    /// The allocation itself failed, with the errno the backend left.
+    AllocationFailed(Option<io::Error>),
+}
+
+impl fmt::Display for AllocFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AllocFailure::FormatUnsupported => write!(f, "format and flags unsupported"),
+            AllocFailure::AllocationFailed(Some(ref e)) => write!(f, "allocation failed: {}", e),
+            AllocFailure::AllocationFailed(None) => write!(f, "allocation failed"),
+        }
+    }
+}
+
+/// A candidate that was tried and failed.
+#[derive(Debug)]
+pub struct AllocAttempt {
+    /// Index into `AllocRequest::candidates`.
+    pub candidate: usize,
+    pub failure: AllocFailure,
+}
+
+/// A successful `allocate_with_fallback`.
+#[derive(Debug)]
+pub struct Allocation {
+    pub bo: Bo,
+    /// Index into `AllocRequest::candidates` of the one that worked.
+    pub candidate: usize,
+    /// The candidates tried before it.
+    pub attempts: Vec<AllocAttempt>,
+}
+
+/// Every candidate failed.
+#[derive(Debug)]
+pub struct FallbackError {
+    pub attempts: Vec<AllocAttempt>,
+}
+
+impl fmt::Display for FallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.attempts.is_empty() {
+            return write!(f, "no allocation candidates given");
+        }
+        write!(f, "all allocation candidates failed")?;
+        for a in &self.attempts {
+            write!(f, "; #{}: {}", a.candidate, a.failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for FallbackError {
+    fn description(&self) -> &str {
+        "all allocation candidates failed"
+    }
+}
+
+/// errno if it was set since `ffi::clear_errno`.
+pub fn take_errno() -> Option<io::Error> {
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(0) | None => None,
+        Some(_) => Some(err),
+    }
+}
+
+impl Device {
+    /// Allocates with the first candidate of `request` that works.
+    pub fn allocate_with_fallback(&self, request: &AllocRequest)
+                                  -> Result<Allocation, FallbackError> {
+        let mut attempts = Vec::new();
+        for (i, c) in request.candidates.iter().enumerate() {
+            match self.try_candidate(request.width, request.height, c) {
+                Ok(bo) => return Ok(Allocation { bo, candidate: i, attempts }),
+                Err(failure) => attempts.push(AllocAttempt { candidate: i, failure }),
+            }
+        }
+        Err(FallbackError { attempts })
+    }
+
+    fn try_candidate(&self, width: u32, height: u32, c: &AllocCandidate)
+                     -> Result<Bo, AllocFailure> {
+        let format = BoFormat::FourCC(c.format);
+        if !self.is_format_supported(format, c.flags) {
+            return Err(AllocFailure::FormatUnsupported);
+        }
+        // The modifiers go to the driver as they are: those without the
+        // plane count query know none of them by it, yet allocate fine.
+        // `create_bo` reads errno before anything else can clobber it.
+        self.create_bo(width, height, format, &c.modifiers, c.flags)
+            .map_err(AllocFailure::AllocationFailed)
+    }
+}
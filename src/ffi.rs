@@ -56,6 +56,31 @@ macro_rules! decl_gbm_fourcc_list {
                 $name = gbm_fourcc_code!($a, $b, $c, $d),
             )*
         }
+
+        impl FourCC {
+            /// Every format code known to this crate, in declaration order.
+            pub const ALL: &'static [FourCC] = &[ $( FourCC::$name, )* ];
+
+            pub fn from_u32(u: u32) -> Option<FourCC> {
+                $(
+                    if u == FourCC::$name as u32 { return Some(FourCC::$name) }
+                )*
+                None
+            }
+
+            /// The four character code, e.g. `"XR24"`.  Trailing
+            /// padding spaces are kept.
+            pub fn code(&self) -> String {
+                let u = *self as u32;
+                (0..4).map(|i| ((u >> (i * 8)) & 0xff) as u8 as char).collect()
+            }
+
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $( FourCC::$name => stringify!($name), )*
+                }
+            }
+        }
     }
 }
 
@@ -136,6 +161,13 @@ decl_gbm_fourcc_list! {
     NV16 ('N', 'V', '1', '6'), /* 2x1 subsampled Cr:Cb plane */
     NV61 ('N', 'V', '6', '1'), /* 2x1 subsampled Cb:Cr plane */
 
+    /*
+     * 2 plane YCbCr MSB aligned
+     * index 0 = Y plane, [15:0] Y:x 10:6 little endian
+     * index 1 = Cr:Cb plane, [31:0] Cr:x:Cb:x 10:6:10:6 little endian
+     */
+    P010 ('P', '0', '1', '0'), /* 2x2 subsampled Cr:Cb plane 10 bits per channel */
+
     /*
      * 3 plane YCbCr
      * index 0: Y plane, [7:0] Y
@@ -165,12 +197,54 @@ bitflags! {
         const GBM_BO_USE_RENDERING = (1 << 2),
         const GBM_BO_USE_WRITE = (1 << 3),
         const GBM_BO_USE_LINEAR = (1 << 4),
+        // Mesa only; minigbm uses these bits for other usages, see below.
+        #[cfg(not(feature = "minigbm"))]
+        const GBM_BO_USE_PROTECTED = (1 << 5),
+        #[cfg(not(feature = "minigbm"))]
+        const GBM_BO_USE_FRONT_RENDERING = (1 << 6),
+        #[cfg(not(feature = "minigbm"))]
+        const GBM_BO_FIXED_COMPRESSION_MASK = (15 << 7),
     }
 }
 
+// minigbm numbers its usage bits differently from Mesa above bit 4.
+#[cfg(feature = "minigbm")]
+pub mod minigbm {
+    pub const GBM_BO_USE_TEXTURING: u32 = 1 << 5;
+    pub const GBM_BO_USE_CAMERA_WRITE: u32 = 1 << 6;
+    pub const GBM_BO_USE_CAMERA_READ: u32 = 1 << 7;
+    pub const GBM_BO_USE_PROTECTED: u32 = 1 << 8;
+    pub const GBM_BO_USE_SW_READ_OFTEN: u32 = 1 << 9;
+    pub const GBM_BO_USE_SW_READ_RARELY: u32 = 1 << 10;
+    pub const GBM_BO_USE_SW_WRITE_OFTEN: u32 = 1 << 11;
+    pub const GBM_BO_USE_SW_WRITE_RARELY: u32 = 1 << 12;
+    pub const GBM_BO_USE_HW_VIDEO_DECODER: u32 = 1 << 13;
+    pub const GBM_BO_USE_HW_VIDEO_ENCODER: u32 = 1 << 14;
+    pub const GBM_BO_USE_TEST_ALLOC: u32 = 1 << 15;
+    pub const GBM_BO_USE_FRONT_RENDERING: u32 = 1 << 16;
+    pub const GBM_BO_USE_GPU_DATA_BUFFER: u32 = 1 << 17;
+    pub const GBM_BO_USE_SENSOR_DIRECT_DATA: u32 = 1 << 18;
+}
+
+/// Older name for `GBM_BO_USE_CURSOR`.
+pub const GBM_BO_USE_CURSOR_64X64: gbm_bo_flags = GBM_BO_USE_CURSOR;
+
+/// Shift of the 4-bit fixed-rate compression field.  A value of 1 in the
+/// field requests the driver default, `n + 1` requests `n` bits per
+/// component for `n` from 1 to 12.  Mesa only.
+#[cfg(not(feature = "minigbm"))]
+pub const GBM_BO_FIXED_COMPRESSION_SHIFT: u32 = 7;
+
+pub const GBM_BO_TRANSFER_READ: u32 = 1 << 0;
+pub const GBM_BO_TRANSFER_WRITE: u32 = 1 << 1;
+pub const GBM_BO_TRANSFER_READ_WRITE: u32 = GBM_BO_TRANSFER_READ | GBM_BO_TRANSFER_WRITE;
+
 pub const GBM_BO_IMPORT_WL_BUFFER: u32 = 0x5501;
 pub const GBM_BO_IMPORT_EGL_IMAGE: u32 = 0x5502;
 pub const GBM_BO_IMPORT_FD: u32 = 0x5503;
+pub const GBM_BO_IMPORT_FD_MODIFIER: u32 = 0x5504;
+
+pub const GBM_MAX_PLANES: usize = 4;
 
 #[repr(C)]
 pub struct gbm_import_fd_data {
@@ -181,6 +255,217 @@ pub struct gbm_import_fd_data {
     pub format: u32,
 }
 
+/// Format modifier describing a plain linear layout.
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+/// Sentinel meaning "no explicit modifier".
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+macro_rules! fourcc_mod_code {
+    ($vendor:expr, $val:expr) => {
+        (($vendor as u64) << 56) | (($val as u64) & 0x00ff_ffff_ffff_ffff)
+    }
+}
+
+pub const DRM_FORMAT_MOD_VENDOR_INTEL: u64 = 0x01;
+pub const DRM_FORMAT_MOD_VENDOR_AMD: u64 = 0x02;
+pub const DRM_FORMAT_MOD_VENDOR_NVIDIA: u64 = 0x03;
+pub const DRM_FORMAT_MOD_VENDOR_BROADCOM: u64 = 0x07;
+pub const DRM_FORMAT_MOD_VENDOR_ARM: u64 = 0x08;
+
+pub const I915_FORMAT_MOD_X_TILED: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_INTEL, 1);
+pub const I915_FORMAT_MOD_Y_TILED: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_INTEL, 2);
+pub const I915_FORMAT_MOD_YF_TILED: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_INTEL, 3);
+pub const I915_FORMAT_MOD_Y_TILED_CCS: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_INTEL, 4);
+pub const I915_FORMAT_MOD_YF_TILED_CCS: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_INTEL, 5);
+pub const I915_FORMAT_MOD_4_TILED: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_INTEL, 9);
+pub const DRM_FORMAT_MOD_BROADCOM_VC4_T_TILED: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_BROADCOM, 1);
+
+/// AMD swizzle mode `tile` of tiling generation `version`, without DCC
+/// or the device specific pipe and bank XOR bits.
+macro_rules! amd_fmt_mod {
+    ($tile:expr, $version:expr) => {
+        fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_AMD, ($tile << 8) | $version)
+    }
+}
+pub const AMD_FMT_MOD_TILE_VER_GFX9: u64 = 1;
+pub const AMD_FMT_MOD_TILE_GFX9_64K_S: u64 = 9;
+pub const AMD_FMT_MOD_TILE_GFX9_64K_D: u64 = 10;
+pub const AMD_FMT_MOD_GFX9_64K_S: u64 =
+    amd_fmt_mod!(AMD_FMT_MOD_TILE_GFX9_64K_S, AMD_FMT_MOD_TILE_VER_GFX9);
+pub const AMD_FMT_MOD_GFX9_64K_D: u64 =
+    amd_fmt_mod!(AMD_FMT_MOD_TILE_GFX9_64K_D, AMD_FMT_MOD_TILE_VER_GFX9);
+
+pub const DRM_FORMAT_MOD_NVIDIA_TEGRA_TILED: u64 = fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_NVIDIA, 1);
+/// 16Bx2 block linear with blocks `1 << v` GOBs high, for `v` up to 5.
+macro_rules! nvidia_16bx2_block {
+    ($v:expr) => { fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_NVIDIA, 0x10 | ($v & 0xf)) }
+}
+pub const DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_ONE_GOB: u64 = nvidia_16bx2_block!(0);
+pub const DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_TWO_GOB: u64 = nvidia_16bx2_block!(1);
+pub const DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_FOUR_GOB: u64 = nvidia_16bx2_block!(2);
+pub const DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_EIGHT_GOB: u64 = nvidia_16bx2_block!(3);
+pub const DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_SIXTEEN_GOB: u64 = nvidia_16bx2_block!(4);
+pub const DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_THIRTYTWO_GOB: u64 = nvidia_16bx2_block!(5);
+
+/// `AFBC_FORMAT_MOD_BLOCK_SIZE_16x16`.
+pub const AFBC_FORMAT_MOD_BLOCK_SIZE_16X16: u64 = 1;
+/// `AFBC_FORMAT_MOD_BLOCK_SIZE_32x8`.
+pub const AFBC_FORMAT_MOD_BLOCK_SIZE_32X8: u64 = 2;
+pub const AFBC_FORMAT_MOD_YTR: u64 = 1 << 4;
+pub const AFBC_FORMAT_MOD_SPLIT: u64 = 1 << 5;
+pub const AFBC_FORMAT_MOD_SPARSE: u64 = 1 << 6;
+/// AFBC with the given `AFBC_FORMAT_MOD_*` mode bits.
+macro_rules! drm_format_mod_arm_afbc {
+    ($mode:expr) => { fourcc_mod_code!(DRM_FORMAT_MOD_VENDOR_ARM, $mode) }
+}
+pub const DRM_FORMAT_MOD_ARM_AFBC_16X16_SPARSE: u64 =
+    drm_format_mod_arm_afbc!(AFBC_FORMAT_MOD_BLOCK_SIZE_16X16 | AFBC_FORMAT_MOD_SPARSE);
+pub const DRM_FORMAT_MOD_ARM_AFBC_16X16_YTR_SPARSE: u64 =
+    drm_format_mod_arm_afbc!(AFBC_FORMAT_MOD_BLOCK_SIZE_16X16 | AFBC_FORMAT_MOD_YTR
+                             | AFBC_FORMAT_MOD_SPARSE);
+pub const DRM_FORMAT_MOD_ARM_AFBC_16X16_YTR_SPARSE_SPLIT: u64 =
+    drm_format_mod_arm_afbc!(AFBC_FORMAT_MOD_BLOCK_SIZE_16X16 | AFBC_FORMAT_MOD_YTR
+                             | AFBC_FORMAT_MOD_SPARSE | AFBC_FORMAT_MOD_SPLIT);
+pub const DRM_FORMAT_MOD_ARM_AFBC_32X8_YTR_SPARSE_SPLIT: u64 =
+    drm_format_mod_arm_afbc!(AFBC_FORMAT_MOD_BLOCK_SIZE_32X8 | AFBC_FORMAT_MOD_YTR
+                             | AFBC_FORMAT_MOD_SPARSE | AFBC_FORMAT_MOD_SPLIT);
+
+/// Modifiers probed when building a capability report.  GBM has no
+/// call that enumerates modifiers, so we ask about the ones we know.
+pub const KNOWN_MODIFIERS: &[u64] = &[
+    DRM_FORMAT_MOD_LINEAR,
+    I915_FORMAT_MOD_X_TILED,
+    I915_FORMAT_MOD_Y_TILED,
+    I915_FORMAT_MOD_YF_TILED,
+    I915_FORMAT_MOD_Y_TILED_CCS,
+    I915_FORMAT_MOD_YF_TILED_CCS,
+    I915_FORMAT_MOD_4_TILED,
+    AMD_FMT_MOD_GFX9_64K_S,
+    AMD_FMT_MOD_GFX9_64K_D,
+    DRM_FORMAT_MOD_NVIDIA_TEGRA_TILED,
+    DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_ONE_GOB,
+    DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_TWO_GOB,
+    DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_FOUR_GOB,
+    DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_EIGHT_GOB,
+    DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_SIXTEEN_GOB,
+    DRM_FORMAT_MOD_NVIDIA_16BX2_BLOCK_THIRTYTWO_GOB,
+    DRM_FORMAT_MOD_BROADCOM_VC4_T_TILED,
+    DRM_FORMAT_MOD_ARM_AFBC_16X16_SPARSE,
+    DRM_FORMAT_MOD_ARM_AFBC_16X16_YTR_SPARSE,
+    DRM_FORMAT_MOD_ARM_AFBC_16X16_YTR_SPARSE_SPLIT,
+    DRM_FORMAT_MOD_ARM_AFBC_32X8_YTR_SPARSE_SPLIT,
+];
+
+#[repr(C)]
+pub struct gbm_import_fd_modifier_data {
+    pub width: u32,
+    pub height: u32,
+    pub format: u32,
+    pub num_fds: u32,
+    pub fds: [c_int; GBM_MAX_PLANES],
+    pub strides: [c_int; GBM_MAX_PLANES],
+    pub offsets: [c_int; GBM_MAX_PLANES],
+    pub modifier: u64,
+}
+
+/// `struct dma_buf_sync` from `linux/dma-buf.h`.
+#[repr(C)]
+pub struct dma_buf_sync {
+    pub flags: u64,
+}
+
+pub const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+pub const DMA_BUF_SYNC_WRITE: u64 = 1 << 1;
+pub const DMA_BUF_SYNC_RW: u64 = DMA_BUF_SYNC_READ | DMA_BUF_SYNC_WRITE;
+pub const DMA_BUF_SYNC_START: u64 = 0 << 2;
+pub const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+/// `_IOW('b', 0, struct dma_buf_sync)`
+pub const DMA_BUF_IOCTL_SYNC: u64 = 0x4008_6200;
+
+/// `struct dma_buf_export_sync_file` and `struct dma_buf_import_sync_file`,
+/// which share a layout.
+#[repr(C)]
+pub struct dma_buf_sync_file {
+    pub flags: u32,
+    pub fd: i32,
+}
+
+/// `_IOWR('b', 2, struct dma_buf_export_sync_file)`
+pub const DMA_BUF_IOCTL_EXPORT_SYNC_FILE: u64 = 0xc008_6202;
+/// `_IOW('b', 3, struct dma_buf_import_sync_file)`
+pub const DMA_BUF_IOCTL_IMPORT_SYNC_FILE: u64 = 0x4008_6203;
+
+/// `struct sync_merge_data` from `linux/sync_file.h`.
+#[repr(C)]
+pub struct sync_merge_data {
+    pub name: [u8; 32],
+    pub fd2: i32,
+    pub fence: i32,
+    pub flags: u32,
+    pub pad: u32,
+}
+
+/// `struct sync_fence_info` from `linux/sync_file.h`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct sync_fence_info {
+    pub obj_name: [u8; 32],
+    pub driver_name: [u8; 32],
+    pub status: i32,
+    pub flags: u32,
+    pub timestamp_ns: u64,
+}
+
+/// `struct sync_file_info` from `linux/sync_file.h`.
+#[repr(C)]
+pub struct sync_file_info {
+    pub name: [u8; 32],
+    pub status: i32,
+    pub flags: u32,
+    pub num_fences: u32,
+    pub pad: u32,
+    pub sync_fence_info: u64,
+}
+
+/// `_IOWR('>', 3, struct sync_merge_data)`
+pub const SYNC_IOC_MERGE: u64 = 0xc030_3e03;
+/// `_IOWR('>', 4, struct sync_file_info)`
+pub const SYNC_IOC_FILE_INFO: u64 = 0xc038_3e04;
+
+/// `struct udmabuf_create` from `linux/udmabuf.h`.
+#[repr(C)]
+pub struct udmabuf_create {
+    pub memfd: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub size: u64,
+}
+
+pub const UDMABUF_FLAGS_CLOEXEC: u32 = 0x01;
+
+/// `_IOW('u', 0x42, struct udmabuf_create)`
+pub const UDMABUF_CREATE: u64 = 0x4018_7542;
+
+/// Zeroes errno, so that a failure can be told from one left over by an
+/// earlier call. Read it back with `io::Error::last_os_error`.
+pub fn clear_errno() {
+    unsafe { *errno_location() = 0 }
+}
+
+#[cfg(any(target_os = "linux", target_os = "dragonfly", target_os = "emscripten",
+          target_os = "fuchsia", target_os = "redox", target_os = "hurd"))]
+unsafe fn errno_location() -> *mut c_int { ::libc::__errno_location() }
+
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
+unsafe fn errno_location() -> *mut c_int { ::libc::__error() }
+
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno_location() -> *mut c_int { ::libc::__errno() }
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+unsafe fn errno_location() -> *mut c_int { ::libc::___errno() }
+
 pub type BoCallback = extern fn(bo: *mut gbm_bo, data: *mut c_void);
 
 #[link(name = "gbm")]
@@ -189,6 +474,9 @@ extern {
     pub fn gbm_device_get_backend_name(gbm: *mut gbm_device) -> *const c_char;
     pub fn gbm_device_is_format_supported(gbm: *mut gbm_device,
                                           format: u32, usage: u32) -> c_int;
+    pub fn gbm_device_get_format_modifier_plane_count(gbm: *mut gbm_device,
+                                                      format: u32,
+                                                      modifier: u64) -> c_int;
     pub fn gbm_device_destroy(gbm: *mut gbm_device);
     pub fn gbm_create_device(fd: RawFd) -> *mut gbm_device;
 
@@ -196,6 +484,16 @@ extern {
                          width: u32,  height: u32,
                          format: u32, flags: u32)
                          -> *mut gbm_bo;
+    pub fn gbm_bo_create_with_modifiers(gbm: *mut gbm_device,
+                                        width: u32, height: u32, format: u32,
+                                        modifiers: *const u64, count: u32)
+                                        -> *mut gbm_bo;
+    /// Mesa 21.3 and later only.
+    #[cfg(feature = "modifiers2")]
+    pub fn gbm_bo_create_with_modifiers2(gbm: *mut gbm_device,
+                                         width: u32, height: u32, format: u32,
+                                         modifiers: *const u64, count: u32,
+                                         flags: u32) -> *mut gbm_bo;
     pub fn gbm_bo_import(gbm: *mut gbm_device, type_: u32,
                          buffer: *mut c_void, usage: u32) -> *mut gbm_bo;
     pub fn gbm_bo_get_width(bo: *mut gbm_bo) -> u32;
@@ -205,10 +503,26 @@ extern {
     pub fn gbm_bo_get_device(bo: *mut gbm_bo) -> *mut gbm_device;
     pub fn gbm_bo_get_handle(bo: *mut gbm_bo) -> gbm_bo_handle;
     pub fn gbm_bo_get_fd(bo: *mut gbm_bo) -> RawFd;
+    pub fn gbm_bo_get_fd_for_plane(bo: *mut gbm_bo, plane: c_int) -> RawFd;
+    pub fn gbm_bo_get_modifier(bo: *mut gbm_bo) -> u64;
+    pub fn gbm_bo_get_offset(bo: *mut gbm_bo, plane: c_int) -> u32;
+    pub fn gbm_bo_get_stride_for_plane(bo: *mut gbm_bo, plane: c_int) -> u32;
+    pub fn gbm_bo_get_plane_count(bo: *mut gbm_bo) -> c_int;
+    pub fn gbm_bo_get_handle_for_plane(bo: *mut gbm_bo, plane: c_int) -> gbm_bo_handle;
     pub fn gbm_bo_write(bo: *mut gbm_bo, buf: *const c_void, count: size_t) -> c_int;
     pub fn gbm_bo_set_user_data(bo: *mut gbm_bo, data: *mut c_void,
-                                destroy_user_data: BoCallback);
+                                destroy_user_data: Option<BoCallback>);
     pub fn gbm_bo_get_user_data(bo: *mut gbm_bo) -> *mut c_void;
+    pub fn gbm_bo_map(bo: *mut gbm_bo,
+                      x: u32, y: u32, width: u32, height: u32,
+                      flags: u32, stride: *mut u32,
+                      map_data: *mut *mut c_void) -> *mut c_void;
+    #[cfg(feature = "minigbm")]
+    pub fn gbm_bo_map2(bo: *mut gbm_bo,
+                       x: u32, y: u32, width: u32, height: u32,
+                       flags: u32, stride: *mut u32,
+                       map_data: *mut *mut c_void, plane: c_int) -> *mut c_void;
+    pub fn gbm_bo_unmap(bo: *mut gbm_bo, map_data: *mut c_void);
     pub fn gbm_bo_destroy(bo: *mut gbm_bo);
     pub fn gbm_surface_create(gbm: *mut gbm_device,
                               width: u32, height: u32,
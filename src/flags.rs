@@ -0,0 +1,478 @@
+//! Usage flags passed when creating or importing buffers.
+
+use std::error;
+use std::fmt;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not, Sub, SubAssign};
+use std::str::FromStr;
+
+use ffi;
+
+/// Set of `gbm_bo_flags`.
+///
+/// Unlike `ffi::gbm_bo_flags` this keeps bits it does not know about,
+/// so vendor specific usage bits survive a round trip through
+/// `from_bits`, `Display` and `FromStr`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct BoFlags {
+    bits: u32,
+}
+
+/// Named usage bits, in bit order.  These are the names accepted by
+/// `FromStr` and produced by `Display`.
+#[cfg(not(feature = "minigbm"))]
+const NAMED: &[(&str, BoFlags)] = &[
+    ("scanout", BoFlags::SCANOUT),
+    ("cursor", BoFlags::CURSOR),
+    ("rendering", BoFlags::RENDERING),
+    ("write", BoFlags::WRITE),
+    ("linear", BoFlags::LINEAR),
+    ("protected", BoFlags::PROTECTED),
+    ("front_rendering", BoFlags::FRONT_RENDERING),
+];
+#[cfg(feature = "minigbm")]
+const NAMED: &[(&str, BoFlags)] = &[
+    ("scanout", BoFlags::SCANOUT),
+    ("cursor", BoFlags::CURSOR),
+    ("rendering", BoFlags::RENDERING),
+    ("write", BoFlags::WRITE),
+    ("linear", BoFlags::LINEAR),
+    ("texturing", BoFlags::TEXTURING),
+    ("camera_write", BoFlags::CAMERA_WRITE),
+    ("camera_read", BoFlags::CAMERA_READ),
+    ("protected", BoFlags::PROTECTED),
+    ("sw_read_often", BoFlags::SW_READ_OFTEN),
+    ("sw_read_rarely", BoFlags::SW_READ_RARELY),
+    ("sw_write_often", BoFlags::SW_WRITE_OFTEN),
+    ("sw_write_rarely", BoFlags::SW_WRITE_RARELY),
+    ("hw_video_decoder", BoFlags::HW_VIDEO_DECODER),
+    ("hw_video_encoder", BoFlags::HW_VIDEO_ENCODER),
+    ("test_alloc", BoFlags::TEST_ALLOC),
+    ("front_rendering", BoFlags::FRONT_RENDERING),
+    ("gpu_data_buffer", BoFlags::GPU_DATA_BUFFER),
+    ("sensor_direct_data", BoFlags::SENSOR_DIRECT_DATA),
+];
+
+// minigbm uses these bits for other usages and has no fixed-rate
+// compression.
+#[cfg(not(feature = "minigbm"))]
+const COMPRESSION_MASK: u32 = 15 << ffi::GBM_BO_FIXED_COMPRESSION_SHIFT;
+#[cfg(feature = "minigbm")]
+const COMPRESSION_MASK: u32 = 0;
+
+impl BoFlags {
+    pub const SCANOUT: BoFlags = BoFlags { bits: 1 << 0 };
+    pub const CURSOR: BoFlags = BoFlags { bits: 1 << 1 };
+    pub const RENDERING: BoFlags = BoFlags { bits: 1 << 2 };
+    pub const WRITE: BoFlags = BoFlags { bits: 1 << 3 };
+    pub const LINEAR: BoFlags = BoFlags { bits: 1 << 4 };
+    #[cfg(not(feature = "minigbm"))]
+    pub const PROTECTED: BoFlags = BoFlags { bits: 1 << 5 };
+    #[cfg(not(feature = "minigbm"))]
+    pub const FRONT_RENDERING: BoFlags = BoFlags { bits: 1 << 6 };
+    /// Fixed-rate compression at the driver's default rate.
+    #[cfg(not(feature = "minigbm"))]
+    pub const FIXED_COMPRESSION_DEFAULT: BoFlags =
+        BoFlags { bits: 1 << ffi::GBM_BO_FIXED_COMPRESSION_SHIFT };
+
+    #[cfg(feature = "minigbm")]
+    pub const TEXTURING: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_TEXTURING };
+    #[cfg(feature = "minigbm")]
+    pub const CAMERA_WRITE: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_CAMERA_WRITE };
+    #[cfg(feature = "minigbm")]
+    pub const CAMERA_READ: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_CAMERA_READ };
+    #[cfg(feature = "minigbm")]
+    pub const PROTECTED: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_PROTECTED };
+    #[cfg(feature = "minigbm")]
+    pub const SW_READ_OFTEN: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_SW_READ_OFTEN };
+    #[cfg(feature = "minigbm")]
+    pub const SW_READ_RARELY: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_SW_READ_RARELY };
+    #[cfg(feature = "minigbm")]
+    pub const SW_WRITE_OFTEN: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_SW_WRITE_OFTEN };
+    #[cfg(feature = "minigbm")]
+    pub const SW_WRITE_RARELY: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_SW_WRITE_RARELY };
+    #[cfg(feature = "minigbm")]
+    pub const HW_VIDEO_DECODER: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_HW_VIDEO_DECODER };
+    #[cfg(feature = "minigbm")]
+    pub const HW_VIDEO_ENCODER: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_HW_VIDEO_ENCODER };
+    /// Only checks whether the allocation would succeed.
+    #[cfg(feature = "minigbm")]
+    pub const TEST_ALLOC: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_TEST_ALLOC };
+    #[cfg(feature = "minigbm")]
+    pub const FRONT_RENDERING: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_FRONT_RENDERING };
+    #[cfg(feature = "minigbm")]
+    pub const GPU_DATA_BUFFER: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_GPU_DATA_BUFFER };
+    #[cfg(feature = "minigbm")]
+    pub const SENSOR_DIRECT_DATA: BoFlags = BoFlags { bits: ffi::minigbm::GBM_BO_USE_SENSOR_DIRECT_DATA };
+
+    pub fn new() -> BoFlags {
+        BoFlags::empty()
+    }
+    pub fn empty() -> BoFlags {
+        BoFlags { bits: 0 }
+    }
+    /// Keeps every bit, including ones this crate has no name for.
+    pub fn from_bits(bits: u32) -> BoFlags {
+        BoFlags { bits }
+    }
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+    pub fn contains(&self, other: BoFlags) -> bool {
+        self.bits & other.bits == other.bits
+    }
+    pub fn insert(&mut self, other: BoFlags) {
+        self.bits |= other.bits
+    }
+    pub fn remove(&mut self, other: BoFlags) {
+        self.bits &= !other.bits
+    }
+    /// Bits that have no name in this crate.
+    pub fn unknown_bits(&self) -> u32 {
+        let known = NAMED.iter().fold(COMPRESSION_MASK, |acc, &(_, f)| acc | f.bits);
+        self.bits & !known
+    }
+
+    /// Every named usage flag, in bit order.
+    pub fn named() -> &'static [(&'static str, BoFlags)] {
+        NAMED
+    }
+
+    /// Fixed-rate compression requested by these flags: `Some(0)` for the
+    /// driver default, `Some(n)` for `n` bits per component.
+    #[cfg(not(feature = "minigbm"))]
+    pub fn fixed_compression(&self) -> Option<u32> {
+        match (self.bits & COMPRESSION_MASK) >> ffi::GBM_BO_FIXED_COMPRESSION_SHIFT {
+            0 => None,
+            1 => Some(0),
+            n => Some(n - 1),
+        }
+    }
+    /// Requests fixed-rate compression.  `Some(0)` asks for the driver
+    /// default, `Some(n)` for `n` (1 to 12) bits per component.
+    #[cfg(not(feature = "minigbm"))]
+    pub fn set_fixed_compression(&mut self, rate: Option<u32>) -> &mut BoFlags {
+        let field = match rate {
+            None => 0,
+            Some(n) => {
+                assert!(n <= 12, "fixed compression rate must be 0..=12 bpc");
+                n + 1
+            }
+        };
+        self.bits = (self.bits & !COMPRESSION_MASK)
+            | (field << ffi::GBM_BO_FIXED_COMPRESSION_SHIFT);
+        self
+    }
+
+    pub fn scanout(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::SCANOUT, v)
+    }
+    pub fn cursor(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::CURSOR, v)
+    }
+    pub fn rendering(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::RENDERING, v)
+    }
+    pub fn write(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::WRITE, v)
+    }
+    pub fn linear(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::LINEAR, v)
+    }
+    /// Bit 5 for Mesa, bit 8 for minigbm.
+    pub fn protected(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::PROTECTED, v)
+    }
+    /// Bit 6 for Mesa, bit 16 for minigbm.
+    pub fn front_rendering(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::FRONT_RENDERING, v)
+    }
+    #[cfg(feature = "minigbm")]
+    pub fn texturing(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::TEXTURING, v)
+    }
+    /// Sets both `SW_READ_OFTEN` and `SW_WRITE_OFTEN`, which minigbm
+    /// needs before it will hand out a CPU mapping that is not a slow
+    /// uncached copy.
+    #[cfg(feature = "minigbm")]
+    pub fn sw_often(&mut self, v: bool) -> &mut BoFlags {
+        self.set(BoFlags::SW_READ_OFTEN | BoFlags::SW_WRITE_OFTEN, v)
+    }
+
+    pub fn set(&mut self, flag: BoFlags, v: bool) -> &mut BoFlags {
+        if v { self.insert(flag) } else { self.remove(flag) }
+        self
+    }
+}
+
+impl From<ffi::gbm_bo_flags> for BoFlags {
+    fn from(flags: ffi::gbm_bo_flags) -> BoFlags {
+        BoFlags::from_bits(flags.bits())
+    }
+}
+
+impl BitOr for BoFlags {
+    type Output = BoFlags;
+    fn bitor(self, rhs: BoFlags) -> BoFlags {
+        BoFlags { bits: self.bits | rhs.bits }
+    }
+}
+impl BitOrAssign for BoFlags {
+    fn bitor_assign(&mut self, rhs: BoFlags) {
+        self.bits |= rhs.bits
+    }
+}
+impl BitAnd for BoFlags {
+    type Output = BoFlags;
+    fn bitand(self, rhs: BoFlags) -> BoFlags {
+        BoFlags { bits: self.bits & rhs.bits }
+    }
+}
+impl BitAndAssign for BoFlags {
+    fn bitand_assign(&mut self, rhs: BoFlags) {
+        self.bits &= rhs.bits
+    }
+}
+impl Sub for BoFlags {
+    type Output = BoFlags;
+    fn sub(self, rhs: BoFlags) -> BoFlags {
+        BoFlags { bits: self.bits & !rhs.bits }
+    }
+}
+impl SubAssign for BoFlags {
+    fn sub_assign(&mut self, rhs: BoFlags) {
+        self.bits &= !rhs.bits
+    }
+}
+impl Not for BoFlags {
+    type Output = BoFlags;
+    fn not(self) -> BoFlags {
+        BoFlags { bits: !self.bits }
+    }
+}
+
+/// Formats as `name|name|0xbits`, or `none` when empty.
+impl fmt::Display for BoFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        let mut sep = "";
+        for &(name, flag) in NAMED {
+            if self.contains(flag) {
+                write!(f, "{}{}", sep, name)?;
+                sep = "|";
+            }
+        }
+        #[cfg(not(feature = "minigbm"))]
+        match self.fixed_compression() {
+            None => {}
+            Some(0) => {
+                write!(f, "{}fixed_compression", sep)?;
+                sep = "|";
+            }
+            Some(n) if n <= 12 => {
+                write!(f, "{}fixed_compression_{}bpc", sep, n)?;
+                sep = "|";
+            }
+            // Reserved field values, kept as raw bits.
+            Some(_) => {
+                write!(f, "{}0x{:x}", sep, self.bits & COMPRESSION_MASK)?;
+                sep = "|";
+            }
+        }
+        let unknown = self.unknown_bits();
+        if unknown != 0 {
+            write!(f, "{}0x{:x}", sep, unknown)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseFlagsError {
+    token: String,
+}
+impl fmt::Display for ParseFlagsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown usage flag `{}`", self.token)
+    }
+}
+impl error::Error for ParseFlagsError {
+    fn description(&self) -> &str {
+        "unknown usage flag"
+    }
+}
+
+/// Parses the `Display` format.  Names are case insensitive, may be
+/// surrounded by whitespace, and hex numbers (`0x100`) are accepted for
+/// bits without a name.
+impl FromStr for BoFlags {
+    type Err = ParseFlagsError;
+    fn from_str(s: &str) -> Result<BoFlags, ParseFlagsError> {
+        let mut flags = BoFlags::empty();
+        for token in s.split('|').map(str::trim) {
+            let lower = token.to_lowercase();
+            let err = || ParseFlagsError { token: token.to_owned() };
+            if lower.is_empty() || lower == "none" {
+                continue;
+            }
+            if let Some(&(_, flag)) = NAMED.iter().find(|&&(name, _)| name == lower) {
+                flags.insert(flag);
+            } else if let Some(rate) = parse_compression(&lower) {
+                flags.insert(BoFlags::from_bits(rate.map_err(|_| err())?));
+            } else if let Some(hex) = lower.strip_prefix("0x") {
+                let bits = u32::from_str_radix(hex, 16).map_err(|_| err())?;
+                flags.insert(BoFlags::from_bits(bits));
+            } else {
+                return Err(err());
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// Recognises `fixed_compression` and `fixed_compression_<n>bpc`,
+/// returning the bits they stand for.
+#[cfg(not(feature = "minigbm"))]
+fn parse_compression(token: &str) -> Option<Result<u32, ()>> {
+    if token == "fixed_compression" {
+        return Some(Ok(BoFlags::FIXED_COMPRESSION_DEFAULT.bits));
+    }
+    if !token.starts_with("fixed_compression_") || !token.ends_with("bpc") {
+        return None;
+    }
+    let rate = token["fixed_compression_".len()..token.len() - 3].parse::<u32>();
+    Some(match rate {
+        Ok(n) if (1..=12).contains(&n) => {
+            Ok(BoFlags::new().set_fixed_compression(Some(n)).bits)
+        }
+        _ => Err(()),
+    })
+}
+#[cfg(feature = "minigbm")]
+fn parse_compression(_token: &str) -> Option<Result<u32, ()>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_round_trip() {
+        for &(name, flag) in BoFlags::named() {
+            assert_eq!(flag.to_string(), name);
+            assert_eq!(name.parse::<BoFlags>(), Ok(flag));
+            assert_eq!(name.to_uppercase().parse::<BoFlags>(), Ok(flag));
+        }
+        let all = BoFlags::named().iter().fold(BoFlags::empty(), |acc, &(_, f)| acc | f);
+        assert_eq!(all.to_string().parse::<BoFlags>(), Ok(all));
+        assert_eq!(all.unknown_bits(), 0);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(BoFlags::empty().to_string(), "none");
+        assert_eq!("none".parse::<BoFlags>(), Ok(BoFlags::empty()));
+        assert_eq!("".parse::<BoFlags>(), Ok(BoFlags::empty()));
+    }
+
+    #[test]
+    fn separators_and_whitespace() {
+        assert_eq!(" Scanout | LINEAR ||".parse::<BoFlags>(),
+                   Ok(BoFlags::SCANOUT | BoFlags::LINEAR));
+    }
+
+    #[test]
+    fn unknown_bits_preserved() {
+        let flags = BoFlags::from_bits(BoFlags::SCANOUT.bits() | 1 << 30 | 1 << 24);
+        assert_eq!(flags.unknown_bits(), 1 << 30 | 1 << 24);
+        assert_eq!(flags.to_string(), "scanout|0x41000000");
+        assert_eq!(flags.to_string().parse::<BoFlags>(), Ok(flags));
+        assert_eq!(BoFlags::from_bits(flags.bits()), flags);
+    }
+
+    #[test]
+    fn bad_names_rejected() {
+        for s in &["bogus", "scanout|bogus", "0x", "0xzz", "0x1ffffffff", "scan out"] {
+            let err = s.parse::<BoFlags>().unwrap_err();
+            assert!(s.contains(&err.token), "{}: {}", s, err);
+        }
+    }
+
+    /// The values of minigbm's `gbm.h`.
+    #[cfg(feature = "minigbm")]
+    #[test]
+    fn minigbm_bits() {
+        let bits = [
+            (BoFlags::SCANOUT, 0), (BoFlags::CURSOR, 1), (BoFlags::RENDERING, 2),
+            (BoFlags::WRITE, 3), (BoFlags::LINEAR, 4), (BoFlags::TEXTURING, 5),
+            (BoFlags::CAMERA_WRITE, 6), (BoFlags::CAMERA_READ, 7), (BoFlags::PROTECTED, 8),
+            (BoFlags::SW_READ_OFTEN, 9), (BoFlags::SW_READ_RARELY, 10),
+            (BoFlags::SW_WRITE_OFTEN, 11), (BoFlags::SW_WRITE_RARELY, 12),
+            (BoFlags::HW_VIDEO_DECODER, 13), (BoFlags::HW_VIDEO_ENCODER, 14),
+            (BoFlags::TEST_ALLOC, 15), (BoFlags::FRONT_RENDERING, 16),
+            (BoFlags::GPU_DATA_BUFFER, 17), (BoFlags::SENSOR_DIRECT_DATA, 18),
+        ];
+        for &(flag, bit) in &bits {
+            assert_eq!(flag.bits(), 1 << bit, "{}", flag);
+        }
+        assert_eq!(BoFlags::named().len(), bits.len());
+    }
+
+    #[cfg(not(feature = "minigbm"))]
+    #[test]
+    fn compression_field_edges() {
+        let field = |n: u32| BoFlags::from_bits(n << ffi::GBM_BO_FIXED_COMPRESSION_SHIFT);
+        assert_eq!(field(0).fixed_compression(), None);
+        assert_eq!(field(1).fixed_compression(), Some(0));
+        assert_eq!(field(1), BoFlags::FIXED_COMPRESSION_DEFAULT);
+        assert_eq!(field(1).to_string(), "fixed_compression");
+        assert_eq!(field(2).to_string(), "fixed_compression_1bpc");
+        assert_eq!(field(13).fixed_compression(), Some(12));
+        assert_eq!(field(13).to_string(), "fixed_compression_12bpc");
+        for n in 0..16 {
+            let flags = field(n) | BoFlags::SCANOUT;
+            assert_eq!(flags.unknown_bits(), 0);
+            assert_eq!(flags.to_string().parse::<BoFlags>(), Ok(flags), "field {}", n);
+        }
+    }
+
+    #[cfg(not(feature = "minigbm"))]
+    #[test]
+    fn compression_parsing() {
+        assert_eq!("fixed_compression".parse::<BoFlags>(),
+                   Ok(BoFlags::FIXED_COMPRESSION_DEFAULT));
+        for n in 1..13 {
+            let flags = format!("fixed_compression_{}bpc", n).parse::<BoFlags>().unwrap();
+            assert_eq!(flags.fixed_compression(), Some(n));
+        }
+        for s in &["fixed_compression_0bpc", "fixed_compression_13bpc",
+                   "fixed_compression_bpc", "fixed_compression_-1bpc",
+                   "fixed_compression_4"] {
+            assert!(s.parse::<BoFlags>().is_err(), "{}", s);
+        }
+    }
+
+    #[cfg(not(feature = "minigbm"))]
+    #[test]
+    fn set_fixed_compression() {
+        let mut flags = BoFlags::SCANOUT;
+        flags.set_fixed_compression(Some(12));
+        assert_eq!(flags.fixed_compression(), Some(12));
+        flags.set_fixed_compression(Some(0));
+        assert_eq!(flags.fixed_compression(), Some(0));
+        flags.set_fixed_compression(None);
+        assert_eq!(flags, BoFlags::SCANOUT);
+    }
+
+    #[cfg(not(feature = "minigbm"))]
+    #[test]
+    #[should_panic]
+    fn set_fixed_compression_out_of_range() {
+        BoFlags::new().set_fixed_compression(Some(13));
+    }
+}
@@ -0,0 +1,80 @@
+//! Memory layout of the formats in `FourCC`.
+
+use ffi::FourCC;
+
+/// Layout of one plane of a format, as in the kernel's `drm_format_info`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PlaneInfo {
+    /// Bytes per sample.  A sample is a pixel, except in packed YUV
+    /// formats like YUYV, where pairs of pixels share their chroma and
+    /// take 4 bytes together; those have `hsub` 2, so rows of odd width
+    /// round up to a whole pair.
+    pub cpp: u32,
+    /// Horizontal subsampling relative to the first plane.
+    pub hsub: u32,
+    /// Vertical subsampling relative to the first plane.
+    pub vsub: u32,
+}
+
+impl PlaneInfo {
+    /// Bytes in one row of a `width` pixel wide image, unpadded.
+    pub fn row_bytes(&self, width: u32) -> u32 {
+        width.div_ceil(self.hsub) * self.cpp
+    }
+
+    /// Rows of a `height` pixel high image.
+    pub fn rows(&self, height: u32) -> u32 {
+        height.div_ceil(self.vsub)
+    }
+}
+
+macro_rules! plane {
+    ($cpp:expr, $hsub:expr, $vsub:expr) => {
+        PlaneInfo { cpp: $cpp, hsub: $hsub, vsub: $vsub }
+    }
+}
+
+const P8: &[PlaneInfo] = &[plane!(1, 1, 1)];
+const P16: &[PlaneInfo] = &[plane!(2, 1, 1)];
+const P24: &[PlaneInfo] = &[plane!(3, 1, 1)];
+const P32: &[PlaneInfo] = &[plane!(4, 1, 1)];
+
+impl FourCC {
+    /// The planes of the format, in order.
+    pub fn planes(&self) -> &'static [PlaneInfo] {
+        use ffi::FourCC::*;
+        match *self {
+            C8 | RGB332 | BGR233 => P8,
+            XRGB4444 | XBGR4444 | RGBX4444 | BGRX4444 |
+            ARGB4444 | ABGR4444 | RGBA4444 | BGRA4444 |
+            XRGB1555 | XBGR1555 | RGBX5551 | BGRX5551 |
+            ARGB1555 | ABGR1555 | RGBA5551 | BGRA5551 |
+            RGB565 | BGR565 => P16,
+            RGB888 | BGR888 => P24,
+            XRGB8888 | XBGR8888 | RGBX8888 | BGRX8888 |
+            ARGB8888 | ABGR8888 | RGBA8888 | BGRA8888 |
+            XRGB2101010 | XBGR2101010 | RGBX1010102 | BGRX1010102 |
+            ARGB2101010 | ABGR2101010 | RGBA1010102 | BGRA1010102 => P32,
+            YUYV | YVYU | UYVY | VYUY => &[plane!(4, 2, 1)],
+            AYUV => P32,
+            NV12 | NV21 => &[plane!(1, 1, 1), plane!(2, 2, 2)],
+            NV16 | NV61 => &[plane!(1, 1, 1), plane!(2, 2, 1)],
+            P010 => &[plane!(2, 1, 1), plane!(4, 2, 2)],
+            YUV410 | YVU410 => &[plane!(1, 1, 1), plane!(1, 4, 4), plane!(1, 4, 4)],
+            YUV411 | YVU411 => &[plane!(1, 1, 1), plane!(1, 4, 1), plane!(1, 4, 1)],
+            YUV420 | YVU420 => &[plane!(1, 1, 1), plane!(1, 2, 2), plane!(1, 2, 2)],
+            YUV422 | YVU422 => &[plane!(1, 1, 1), plane!(1, 2, 1), plane!(1, 2, 1)],
+            YUV444 | YVU444 => &[plane!(1, 1, 1), plane!(1, 1, 1), plane!(1, 1, 1)],
+        }
+    }
+
+    pub fn is_yuv(&self) -> bool {
+        use ffi::FourCC::*;
+        matches!(*self,
+                 YUYV | YVYU | UYVY | VYUY | AYUV |
+                 NV12 | NV21 | NV16 | NV61 | P010 |
+                 YUV410 | YVU410 | YUV411 | YVU411 |
+                 YUV420 | YVU420 | YUV422 | YVU422 | YUV444 | YVU444)
+    }
+}
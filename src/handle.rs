@@ -0,0 +1,121 @@
+//! Typed access to the `gbm_bo_handle` union.
+//!
+//! What the union holds depends on the backend: every DRM based backend
+//! we know of stores a 32-bit GEM handle, others may store a 64-bit
+//! value.
+
+use std::ffi::CStr;
+
+use libc::c_int;
+
+use {ffi, Bo};
+
+/// Which member of `gbm_bo_handle` a backend fills in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum HandleKind {
+    Gem,
+    U64,
+}
+
+/// Backends known to return GEM handles.
+#[cfg(not(feature = "minigbm"))]
+const GEM_BACKENDS: &[&str] = &["drm", "dri", "gbm_dri", "nvidia"];
+
+impl HandleKind {
+    /// The kind used by the backend called `name`.  Unknown backends
+    /// report `U64`, the widest member.  That keeps whatever they stored,
+    /// but a backend storing only 32 bits leaves the upper half undefined;
+    /// read its handles with `Bo::handle_as(HandleKind::Gem)`.
+    #[cfg(not(feature = "minigbm"))]
+    pub fn for_backend(name: &str) -> HandleKind {
+        if GEM_BACKENDS.contains(&name) { HandleKind::Gem } else { HandleKind::U64 }
+    }
+    /// minigbm names its backend after the kernel driver, and all of them
+    /// use GEM handles.
+    #[cfg(feature = "minigbm")]
+    pub fn for_backend(_name: &str) -> HandleKind {
+        HandleKind::Gem
+    }
+}
+
+/// A buffer handle, interpreted according to its backend.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum BoHandle {
+    /// GEM handle, valid on the device's DRM fd.
+    Gem(u32),
+    U64(u64),
+}
+
+impl BoHandle {
+    pub fn from_raw(kind: HandleKind, raw: ffi::gbm_bo_handle) -> BoHandle {
+        unsafe {
+            match kind {
+                HandleKind::Gem => BoHandle::Gem(raw.u32()),
+                HandleKind::U64 => BoHandle::U64(raw.u64()),
+            }
+        }
+    }
+
+    pub fn as_gem(&self) -> Option<u32> {
+        match *self {
+            BoHandle::Gem(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    /// The handle as an integer, whatever its kind.
+    pub fn as_u64(&self) -> u64 {
+        match *self {
+            BoHandle::Gem(h) => h as u64,
+            BoHandle::U64(h) => h,
+        }
+    }
+}
+
+impl Bo {
+    /// The handle kind of the device this buffer belongs to.
+    pub fn handle_kind(&self) -> HandleKind {
+        let name = unsafe {
+            let dev = ffi::gbm_bo_get_device(self.ptr);
+            CStr::from_ptr(ffi::gbm_device_get_backend_name(dev))
+        };
+        HandleKind::for_backend(&name.to_string_lossy())
+    }
+
+    pub fn handle(&self) -> BoHandle {
+        self.handle_as(self.handle_kind())
+    }
+
+    /// Reads the handle as `kind`, for backends `handle_kind` guesses
+    /// wrong about.
+    pub fn handle_as(&self, kind: HandleKind) -> BoHandle {
+        BoHandle::from_raw(kind, unsafe { ffi::gbm_bo_get_handle(self.ptr) })
+    }
+
+    pub fn plane_count(&self) -> u32 {
+        let _lock = self.dev.lock();
+        let n = unsafe { ffi::gbm_bo_get_plane_count(self.ptr) };
+        if n < 1 { 1 } else { n as u32 }
+    }
+
+    /// Handle of `plane`, or `None` if the buffer has no such plane.
+    pub fn plane_handle(&self, plane: u32) -> Option<BoHandle> {
+        if plane >= self.plane_count() {
+            return None;
+        }
+        let _lock = self.dev.lock();
+        let raw = unsafe {
+            ffi::gbm_bo_get_handle_for_plane(self.ptr, plane as c_int)
+        };
+        // libgbm reports failure by setting the s32 member to -1.
+        if unsafe { raw.s32() } == -1 {
+            return None;
+        }
+        Some(BoHandle::from_raw(self.handle_kind(), raw))
+    }
+
+    /// Handles of every plane, in order.
+    pub fn plane_handles(&self) -> Vec<BoHandle> {
+        (0..self.plane_count()).filter_map(|p| self.plane_handle(p)).collect()
+    }
+}
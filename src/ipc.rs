@@ -0,0 +1,387 @@
+//! Sending buffers to other processes over Unix sockets.
+//!
+//! A buffer travels as one message: a fixed size header describing the
+//! layout, with the plane fds attached as `SCM_RIGHTS`.  Both stream and
+//! seqpacket sockets work.
+//!
+//! Header layout, native endian:
+//!
+//! | bytes  | field                                  |
+//! |--------|----------------------------------------|
+//! | 0..4   | magic `GBMB`                           |
+//! | 4..6   | version (1)                            |
+//! | 6..8   | plane count                            |
+//! | 8..12  | width                                  |
+//! | 12..16 | height                                 |
+//! | 16..20 | fourcc                                 |
+//! | 20..28 | modifier                               |
+//! | 28..60 | stride and offset of each of 4 planes  |
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+use libc::{self, c_void};
+
+use {ffi, Bo, BoFlags, Device, Dmabuf, DmabufPlane, FourCC};
+
+const MAGIC: u32 = 0x424d_4247; // "GBMB"
+const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 28 + 8 * ffi::GBM_MAX_PLANES;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn encode_header(dmabuf: &Dmabuf) -> [u8; HEADER_LEN] {
+    fn put(buf: &mut [u8], at: usize, bytes: &[u8]) {
+        buf[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+    let mut buf = [0; HEADER_LEN];
+    put(&mut buf, 0, &MAGIC.to_ne_bytes());
+    put(&mut buf, 4, &VERSION.to_ne_bytes());
+    put(&mut buf, 6, &(dmabuf.planes.len() as u16).to_ne_bytes());
+    put(&mut buf, 8, &dmabuf.width.to_ne_bytes());
+    put(&mut buf, 12, &dmabuf.height.to_ne_bytes());
+    put(&mut buf, 16, &(dmabuf.format as u32).to_ne_bytes());
+    put(&mut buf, 20, &dmabuf.modifier.to_ne_bytes());
+    for (i, p) in dmabuf.planes.iter().enumerate() {
+        put(&mut buf, 28 + i * 8, &p.stride.to_ne_bytes());
+        put(&mut buf, 32 + i * 8, &p.offset.to_ne_bytes());
+    }
+    buf
+}
+
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[at..at + 4]);
+    u32::from_ne_bytes(b)
+}
+
+fn decode_header(buf: &[u8; HEADER_LEN], fds: Vec<OwnedFd>) -> io::Result<Dmabuf> {
+    if get_u32(buf, 0) != MAGIC {
+        return Err(invalid("not a gbm buffer message"));
+    }
+    if u16::from_ne_bytes([buf[4], buf[5]]) != VERSION {
+        return Err(invalid("unsupported gbm buffer message version"));
+    }
+    let count = u16::from_ne_bytes([buf[6], buf[7]]) as usize;
+    if count == 0 || count > ffi::GBM_MAX_PLANES || count != fds.len() {
+        return Err(invalid("plane count does not match the fds received"));
+    }
+    let format = FourCC::from_u32(get_u32(buf, 16))
+        .ok_or_else(|| invalid("unknown fourcc"))?;
+    let mut m = [0; 8];
+    m.copy_from_slice(&buf[20..28]);
+    let planes = fds.into_iter().enumerate().map(|(i, fd)| DmabufPlane {
+        fd,
+        stride: get_u32(buf, 28 + i * 8),
+        offset: get_u32(buf, 32 + i * 8),
+    }).collect();
+    Ok(Dmabuf {
+        width: get_u32(buf, 8),
+        height: get_u32(buf, 12),
+        format,
+        modifier: u64::from_ne_bytes(m),
+        planes,
+    })
+}
+
+/// Sends `dmabuf` over the connected Unix socket `sock`.  The fds are
+/// duplicated into the receiver; ours stay open.
+pub fn send_dmabuf<S: AsRawFd>(sock: &S, dmabuf: &Dmabuf) -> io::Result<()> {
+    if dmabuf.planes.is_empty() || dmabuf.planes.len() > ffi::GBM_MAX_PLANES {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad plane count"));
+    }
+    let header = encode_header(dmabuf);
+    let fds: Vec<RawFd> = dmabuf.planes.iter().map(|p| p.fd.as_raw_fd()).collect();
+    send_with_fds(sock.as_raw_fd(), &header, &fds)
+}
+
+/// Sends `bytes` with `fds` attached to the first one.
+fn send_with_fds(sock: RawFd, bytes: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = mem::size_of_val(fds) as u32;
+
+    unsafe {
+        let mut cmsg_buf = vec![0u8; libc::CMSG_SPACE(fds_len) as usize];
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr() as *mut c_void,
+            iov_len: bytes.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = cmsg_buf.len() as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        let n = loop {
+            let n = libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL);
+            if n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break n;
+            }
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The fds went with the first byte; a stream socket may still
+        // need the rest of the message.
+        let mut sent = n as usize;
+        while sent < bytes.len() {
+            let n = libc::send(sock, bytes[sent..].as_ptr() as *const c_void,
+                               bytes.len() - sent, libc::MSG_NOSIGNAL);
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue }
+                return Err(err);
+            }
+            sent += n as usize;
+        }
+    }
+    Ok(())
+}
+
+/// Receives a buffer sent with `send_dmabuf`.  The fds are received
+/// close-on-exec.
+pub fn recv_dmabuf<S: AsRawFd>(sock: &S) -> io::Result<Dmabuf> {
+    let mut header = [0u8; HEADER_LEN];
+    let max_fds_len = (ffi::GBM_MAX_PLANES * mem::size_of::<RawFd>()) as u32;
+    let mut fds = Vec::new();
+
+    let received = unsafe {
+        let mut cmsg_buf = vec![0u8; libc::CMSG_SPACE(max_fds_len) as usize];
+        let mut iov = libc::iovec {
+            iov_base: header.as_mut_ptr() as *mut c_void,
+            iov_len: header.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = cmsg_buf.len() as _;
+
+        let n = loop {
+            let n = libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+            if n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break n;
+            }
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Take ownership of everything we were sent before any checks,
+        // so nothing leaks on the error paths.
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(invalid("too many fds attached to gbm buffer message"));
+        }
+        n as usize
+    };
+
+    if received == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "socket closed"));
+    }
+    if received < header.len() {
+        // Stream sockets may split the header; the rest has no fds.
+        read_exact(sock.as_raw_fd(), &mut header[received..])?;
+    }
+    decode_header(&header, fds)
+}
+
+fn read_exact(fd: RawFd, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { continue }
+            return Err(err);
+        }
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "socket closed inside gbm buffer message"));
+        }
+        let rest = buf;
+        buf = &mut rest[n as usize..];
+    }
+    Ok(())
+}
+
+impl Bo {
+    /// Exports the buffer and sends it with `send_dmabuf`.
+    pub fn send_to<S: AsRawFd>(&self, sock: &S) -> io::Result<()> {
+        let dmabuf = self.export().map_err(io::Error::other)?;
+        send_dmabuf(sock, &dmabuf)
+    }
+}
+
+impl Device {
+    /// Receives a buffer with `recv_dmabuf` and imports it.
+    pub fn receive_bo<S: AsRawFd>(&self, sock: &S, usage: BoFlags) -> io::Result<Bo> {
+        let dmabuf = recv_dmabuf(sock)?;
+        self.import_dmabuf(&dmabuf, usage).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    use libc::{self, c_void};
+
+    use super::*;
+    use {ffi, Dmabuf, DmabufPlane, FourCC};
+
+    fn socketpair(kind: libc::c_int) -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        let r = unsafe { libc::socketpair(libc::AF_UNIX, kind | libc::SOCK_CLOEXEC, 0,
+                                          fds.as_mut_ptr()) };
+        assert_eq!(r, 0, "socketpair: {}", io::Error::last_os_error());
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn memfd(contents: &[u8]) -> OwnedFd {
+        let name = CString::new("gbm-ipc-test").unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0, "memfd_create: {}", io::Error::last_os_error());
+        let n = unsafe { libc::write(fd, contents.as_ptr() as *const c_void, contents.len()) };
+        assert_eq!(n, contents.len() as isize);
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    }
+
+    fn read_at(fd: RawFd, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let n = unsafe { libc::pread(fd, buf.as_mut_ptr() as *mut c_void, len, 0) };
+        assert_eq!(n, len as isize);
+        buf
+    }
+
+    fn nv12() -> Dmabuf {
+        Dmabuf {
+            width: 64,
+            height: 48,
+            format: FourCC::NV12,
+            modifier: ffi::DRM_FORMAT_MOD_LINEAR,
+            planes: vec![
+                DmabufPlane { fd: memfd(b"luma"), offset: 0, stride: 64 },
+                DmabufPlane { fd: memfd(b"chroma"), offset: 64 * 48, stride: 64 },
+            ],
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let dmabuf = nv12();
+        let header = encode_header(&dmabuf);
+        let fds = vec![memfd(b""), memfd(b"")];
+        let decoded = decode_header(&header, fds).unwrap();
+        assert_eq!((decoded.width, decoded.height), (64, 48));
+        assert_eq!(decoded.format, FourCC::NV12);
+        assert_eq!(decoded.modifier, ffi::DRM_FORMAT_MOD_LINEAR);
+        let layout: Vec<_> = decoded.planes.iter().map(|p| (p.offset, p.stride)).collect();
+        assert_eq!(layout, vec![(0, 64), (64 * 48, 64)]);
+    }
+
+    #[test]
+    fn header_rejects_bad_magic_and_version() {
+        let mut header = encode_header(&nv12());
+        header[0] ^= 0xff;
+        let err = decode_header(&header, vec![memfd(b""), memfd(b"")]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut header = encode_header(&nv12());
+        header[4] = 2;
+        header[5] = 0;
+        let err = decode_header(&header, vec![memfd(b""), memfd(b"")]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn send_receive(kind: libc::c_int) {
+        let (a, b) = socketpair(kind);
+        send_dmabuf(&a, &nv12()).unwrap();
+        let received = recv_dmabuf(&b).unwrap();
+        assert_eq!(received.format, FourCC::NV12);
+        assert_eq!(received.planes.len(), 2);
+        assert_eq!(read_at(received.planes[0].fd.as_raw_fd(), 4), b"luma");
+        assert_eq!(read_at(received.planes[1].fd.as_raw_fd(), 6), b"chroma");
+        assert_eq!(received.planes[1].offset, 64 * 48);
+    }
+
+    #[test]
+    fn send_receive_stream() {
+        send_receive(libc::SOCK_STREAM);
+    }
+
+    #[test]
+    fn send_receive_seqpacket() {
+        send_receive(libc::SOCK_SEQPACKET);
+    }
+
+    #[test]
+    fn closed_socket() {
+        let (a, b) = socketpair(libc::SOCK_STREAM);
+        drop(a);
+        let err = recv_dmabuf(&b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn short_read() {
+        let (a, b) = socketpair(libc::SOCK_STREAM);
+        let header = encode_header(&nv12());
+        let fds = [memfd(b""), memfd(b"")];
+        let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        send_with_fds(a.as_raw_fd(), &header[..HEADER_LEN / 2], &raw).unwrap();
+        drop(a);
+        let err = recv_dmabuf(&b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_fds() {
+        let (a, b) = socketpair(libc::SOCK_SEQPACKET);
+        let header = encode_header(&nv12());
+        let fds: Vec<OwnedFd> = (0..ffi::GBM_MAX_PLANES * 2).map(|_| memfd(b"")).collect();
+        let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        send_with_fds(a.as_raw_fd(), &header, &raw).unwrap();
+        let err = recv_dmabuf(&b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn wrong_fd_count() {
+        let (a, b) = socketpair(libc::SOCK_SEQPACKET);
+        let header = encode_header(&nv12());
+        let fd = memfd(b"");
+        send_with_fds(a.as_raw_fd(), &header, &[fd.as_raw_fd()]).unwrap();
+        let err = recv_dmabuf(&b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn bad_plane_count_is_not_sent() {
+        let (a, _b) = socketpair(libc::SOCK_STREAM);
+        let mut dmabuf = nv12();
+        dmabuf.planes.clear();
+        let err = send_dmabuf(&a, &dmabuf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,333 @@
+//! Leak and lifetime diagnostics, enabled by the `leak_check` feature.
+//!
+//! Every `Bo` and `Surface` is registered with its device together with
+//! a backtrace of where it was created, and buffers locked from a surface
+//! are marked as such until released.  `Device::live_objects` lists them
+//! on demand; when the last `Device` handle is dropped while any are
+//! still alive, the device's `LeakPolicy` decides what happens.
+//!
+//! Capturing backtraces makes every allocation slow, so this is meant for
+//! debug builds and tests.
+
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Bo, Device, Surface};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ObjectKind {
+    Bo,
+    Surface,
+    /// A buffer returned by `Surface::lock_front_buffer` and not yet
+    /// released.
+    LockedBuffer,
+}
+
+/// What to do when the last `Device` handle goes away with objects
+/// still alive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum LeakPolicy {
+    Ignore,
+    /// Print the report to stderr.
+    Report,
+    /// Panic with the report, unless the thread is already panicking.
+    Panic,
+}
+
+/// A `Bo` or `Surface` that is still alive.
+#[derive(Clone, Debug)]
+pub struct LiveObject {
+    pub kind: ObjectKind,
+    /// Address of the `gbm_bo` or `gbm_surface`.
+    pub ptr: usize,
+    pub label: Option<String>,
+    /// Time since creation, or since locking for locked buffers.
+    pub age: Duration,
+    /// Where it was created or locked, empty if unknown.
+    pub backtrace: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct LeakReport {
+    /// Oldest first.
+    pub objects: Vec<LiveObject>,
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} live gbm object(s)", self.objects.len())?;
+        for o in &self.objects {
+            write!(f, "{:?} {:#x}", o.kind, o.ptr)?;
+            if let Some(ref label) = o.label {
+                write!(f, " \"{}\"", label)?;
+            }
+            if o.backtrace.is_empty() {
+                writeln!(f, ", alive for {:?}", o.age)?;
+            } else {
+                writeln!(f, ", alive for {:?}, created at:", o.age)?;
+                writeln!(f, "{}", o.backtrace)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    kind: ObjectKind,
+    label: Option<String>,
+    since: Instant,
+    backtrace: Backtrace,
+}
+
+/// The per-device bookkeeping, kept in `DeviceInner`.
+pub struct Registry {
+    objects: Mutex<HashMap<usize, Entry>>,
+    /// Live `Device` handles.
+    handles: AtomicUsize,
+    policy: Mutex<LeakPolicy>,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("objects", &lock(&self.objects).len())
+            .field("handles", &self.handles.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            objects: Mutex::new(HashMap::new()),
+            handles: AtomicUsize::new(0),
+            policy: Mutex::new(LeakPolicy::Report),
+        }
+    }
+
+    pub fn add<T>(&self, kind: ObjectKind, ptr: *mut T) {
+        lock(&self.objects).insert(ptr as usize, Entry {
+            kind,
+            label: None,
+            since: Instant::now(),
+            backtrace: Backtrace::force_capture(),
+        });
+    }
+
+    pub fn remove<T>(&self, ptr: *mut T) {
+        lock(&self.objects).remove(&(ptr as usize));
+    }
+
+    /// Marks a buffer as locked, keeping its label.
+    pub fn locked<T>(&self, ptr: *mut T) {
+        let mut objects = lock(&self.objects);
+        let label = objects.remove(&(ptr as usize)).and_then(|e| e.label);
+        objects.insert(ptr as usize, Entry {
+            kind: ObjectKind::LockedBuffer,
+            label,
+            since: Instant::now(),
+            backtrace: Backtrace::force_capture(),
+        });
+    }
+
+    fn set_label<T>(&self, ptr: *mut T, label: String) {
+        if let Some(e) = lock(&self.objects).get_mut(&(ptr as usize)) {
+            e.label = Some(label);
+        }
+    }
+
+    pub fn handle_created(&self) {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Applies the leak policy if this was the last `Device` handle.
+    pub fn handle_dropped(&self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return;
+        }
+        let report = self.report();
+        if report.is_empty() {
+            return;
+        }
+        match *lock(&self.policy) {
+            LeakPolicy::Ignore => (),
+            LeakPolicy::Report => eprintln!("gbm: device dropped with {}", report),
+            LeakPolicy::Panic if thread::panicking() => (),
+            LeakPolicy::Panic => panic!("gbm: device dropped with {}", report),
+        }
+    }
+
+    fn report(&self) -> LeakReport {
+        let now = Instant::now();
+        let mut objects: Vec<_> = lock(&self.objects).iter().map(|(&ptr, e)| LiveObject {
+            kind: e.kind,
+            ptr,
+            label: e.label.clone(),
+            age: now.duration_since(e.since),
+            backtrace: e.backtrace.to_string(),
+        }).collect();
+        objects.sort_by_key(|o| Reverse(o.age));
+        LeakReport { objects }
+    }
+}
+
+impl Device {
+    /// Every `Bo`, `Surface` and locked buffer of this device still alive.
+    pub fn live_objects(&self) -> LeakReport {
+        self.inner.registry.report()
+    }
+
+    /// Defaults to `LeakPolicy::Report`.
+    pub fn set_leak_policy(&self, policy: LeakPolicy) {
+        *lock(&self.inner.registry.policy) = policy;
+    }
+}
+
+impl Bo {
+    /// Names the buffer in leak reports.
+    pub fn set_debug_label<S: Into<String>>(&self, label: S) {
+        self.dev.registry.set_label(self.ptr, label.into());
+    }
+}
+
+impl Surface {
+    /// Names the surface in leak reports.
+    pub fn set_debug_label<S: Into<String>>(&self, label: S) {
+        self.dev.registry.set_label(self.ptr, label.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    fn ptr(addr: usize) -> *mut u8 {
+        addr as *mut u8
+    }
+
+    /// A registry with one device handle and `objects` registered.
+    fn registry(policy: LeakPolicy, objects: &[usize]) -> Registry {
+        let r = Registry::new();
+        *lock(&r.policy) = policy;
+        r.handle_created();
+        for &o in objects {
+            r.add(ObjectKind::Bo, ptr(o));
+        }
+        r
+    }
+
+    #[test]
+    fn register_unregister() {
+        let r = Registry::new();
+        r.add(ObjectKind::Bo, ptr(0x10));
+        r.add(ObjectKind::Surface, ptr(0x20));
+        r.set_label(ptr(0x10), "cursor".to_owned());
+        r.locked(ptr(0x10));
+        let report = r.report();
+        assert_eq!(report.objects.len(), 2);
+        let bo = report.objects.iter().find(|o| o.ptr == 0x10).unwrap();
+        assert_eq!(bo.kind, ObjectKind::LockedBuffer);
+        assert_eq!(bo.label.as_ref().map(|l| &l[..]), Some("cursor"));
+
+        r.remove(ptr(0x10));
+        r.remove(ptr(0x10));
+        r.remove(ptr(0x20));
+        assert!(r.report().is_empty());
+        // Labelling an unknown object does nothing.
+        r.set_label(ptr(0x30), "gone".to_owned());
+        assert!(r.report().is_empty());
+    }
+
+    #[test]
+    fn report_oldest_first() {
+        let r = registry(LeakPolicy::Ignore, &[0x10]);
+        thread::sleep(Duration::from_millis(5));
+        r.add(ObjectKind::Surface, ptr(0x20));
+        let ptrs: Vec<usize> = r.report().objects.iter().map(|o| o.ptr).collect();
+        assert_eq!(ptrs, [0x10, 0x20]);
+    }
+
+    #[test]
+    fn report_with_backtrace() {
+        let r = registry(LeakPolicy::Ignore, &[0xb0]);
+        let report = r.report();
+        assert!(!report.objects[0].backtrace.is_empty());
+        let text = report.to_string();
+        assert!(text.starts_with("1 live gbm object(s)\nBo 0xb0, alive for "), "{}", text);
+        assert!(text.contains(", created at:\n"), "{}", text);
+    }
+
+    #[test]
+    fn report_without_backtrace() {
+        let report = LeakReport {
+            objects: vec![LiveObject {
+                kind: ObjectKind::LockedBuffer,
+                ptr: 0xb0,
+                label: Some("frame".to_owned()),
+                age: Duration::from_secs(2),
+                backtrace: String::new(),
+            }],
+        };
+        assert_eq!(report.to_string(),
+                   "1 live gbm object(s)\nLockedBuffer 0xb0 \"frame\", alive for 2s\n");
+    }
+
+    #[test]
+    fn policy_ignore() {
+        registry(LeakPolicy::Ignore, &[0x10]).handle_dropped();
+    }
+
+    #[test]
+    fn policy_report() {
+        registry(LeakPolicy::Report, &[0x10]).handle_dropped();
+    }
+
+    #[test]
+    fn policy_panic() {
+        let r = registry(LeakPolicy::Panic, &[0x10]);
+        r.handle_created();
+        // Not the last handle.
+        r.handle_dropped();
+        let payload = panic::catch_unwind(|| r.handle_dropped()).unwrap_err();
+        let msg = payload.downcast::<String>().unwrap();
+        assert!(msg.starts_with("gbm: device dropped with 1 live gbm object(s)"), "{}", msg);
+
+        // Nothing left alive, nothing to panic about.
+        let r = registry(LeakPolicy::Panic, &[]);
+        r.handle_dropped();
+    }
+
+    #[test]
+    fn policy_panic_while_panicking() {
+        struct DropHandle(Registry);
+        impl Drop for DropHandle {
+            fn drop(&mut self) {
+                self.0.handle_dropped();
+            }
+        }
+        let handle = DropHandle(registry(LeakPolicy::Panic, &[0x10]));
+        let payload = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+            let _handle = handle;
+            panic!("first");
+        })).unwrap_err();
+        assert_eq!(*payload.downcast::<&str>().unwrap(), "first");
+    }
+}
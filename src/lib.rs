@@ -1,4 +1,22 @@
 
+//! Rust bindings for libgbm.
+//!
+//! # Threads
+//!
+//! A `Device` is a reference counted handle: cloning it is cheap and
+//! every clone, `Bo` and `Surface` created from it keeps the underlying
+//! `gbm_device` alive.  `Device` is `Send` and `Sync`; `Bo` and `Surface`
+//! are `Send` but not `Sync`, so each buffer is used from one thread at a
+//! time.
+//!
+//! libgbm backends share driver state between all buffers of a device
+//! (Mesa maps buffers through a single per-device context), so every
+//! call that reaches the backend -- allocation, import, mapping,
+//! exporting fds, writing and destruction -- takes a per-device lock.
+//! Plain getters such as `Bo::get_width` read fields of the `gbm_bo` and
+//! run concurrently.  The lock is re-entrant, so destructors run from
+//! inside libgbm may drop other buffers of the same device.
+
 #[macro_use]
 extern crate bitflags;
 extern crate libc;
@@ -6,10 +24,54 @@ extern crate libc;
 extern crate egl;
 #[cfg(feature = "wl_server")]
 extern crate wayland_server;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 
 pub mod ffi;
+mod callback;
+mod capabilities;
+mod damage;
+mod dmabuf;
+mod fallback;
+mod flags;
+mod format;
+mod handle;
+pub mod ipc;
+#[cfg(feature = "leak_check")]
+mod leak;
+mod pool;
+#[cfg(feature = "serde")]
+pub mod serde_impls;
+mod map;
+pub mod pattern;
+mod pixel;
+mod copy;
+#[cfg(feature = "egl_platform")]
+pub mod egl_platform;
+mod sync;
+mod sync_file;
+mod trace;
+pub mod udmabuf;
 
 pub use ffi::FourCC;
+pub use callback::{set_callback_panic_hook, CallbackPanicHook};
+pub use flags::{BoFlags, ParseFlagsError};
+pub use format::PlaneInfo;
+pub use capabilities::{write_json_str, Capabilities, FormatCapabilities, ModifierInfo};
+pub use damage::{Rect, MAX_DAMAGE_HISTORY};
+pub use dmabuf::{CpuAccess, Dmabuf, DmabufMmap, DmabufPlane};
+pub use fallback::{AllocAttempt, AllocCandidate, AllocFailure, AllocRequest, Allocation,
+                   FallbackError};
+pub use handle::{BoHandle, HandleKind};
+#[cfg(feature = "leak_check")]
+pub use leak::{LeakPolicy, LeakReport, LiveObject, ObjectKind};
+pub use pool::{BoPool, PoolKey, PoolStats, PooledBo};
+pub use map::{BoMapping, Transfer};
+pub use sync_file::{FenceInfo, FenceStatus, SyncFile, SyncFileInfo};
 
 #[allow(unused_imports)]
 #[cfg(feature = "wl_server")]
@@ -25,10 +87,21 @@ use std::io;
 use std::any::Any;
 use std::mem::forget;
 use std::fmt;
+use std::ptr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use sync::{DeviceLock, DeviceLockGuard};
 
 #[derive(Debug,Clone,Copy,Eq,PartialEq)]
 pub enum Error {
     DeviceCreation,
+    BoCreation,
+    BoMap,
+    ForeignUserData,
+    BoExport,
+    BoImport,
+    PlaneLayout,
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -39,6 +112,12 @@ impl std::error::Error for Error {
     fn description(&self) -> &str {
         match *self  {
             Error::DeviceCreation => "Failed to create gbm_device",
+            Error::BoCreation => "Failed to create gbm_bo",
+            Error::BoMap => "Failed to map gbm_bo",
+            Error::ForeignUserData => "gbm_bo user data was not set by Rust",
+            Error::BoExport => "Failed to export gbm_bo as dmabuf",
+            Error::BoImport => "Failed to import dmabuf as gbm_bo",
+            Error::PlaneLayout => "Plane layout does not match the format",
         }
     }
 }
@@ -47,31 +126,112 @@ pub type EGLImageKHR = *mut c_void;
 
 type Handle = u32;
 
+/// The `gbm_device` shared by a `Device`, its clones and everything
+/// created from them.  It is destroyed once the last of those is dropped.
 #[derive(Debug)]
-pub struct Device {
+struct DeviceInner {
     ptr: *mut ffi::gbm_device,
+    lock: DeviceLock,
+    /// Addresses of the `UserData` set by `Bo::set_user_data`, keyed by
+    /// buffer.  User data not listed here is never dereferenced.
+    user_data: Mutex<HashMap<usize, usize>>,
+    #[cfg(feature = "leak_check")]
+    registry: leak::Registry,
+}
+unsafe impl Send for DeviceInner {}
+unsafe impl Sync for DeviceInner {}
+
+impl DeviceInner {
+    fn lock(&self) -> DeviceLockGuard<'_> {
+        self.lock.lock()
+    }
+}
+
+impl Drop for DeviceInner {
+    fn drop(&mut self) {
+        trace::device_destroy(self.ptr);
+        unsafe { ffi::gbm_device_destroy(self.ptr) }
+    }
+}
+
+#[derive(Debug)]
+pub struct Device {
+    inner: Arc<DeviceInner>,
+    /// Whether this handle counts towards the live handles of the leak
+    /// registry.  Handles from `Bo::get_device` and `Surface::get_device`
+    /// don't, or dropping them after the user's own would report again.
+    #[cfg(feature = "leak_check")]
+    counted: bool,
+}
+
+impl Clone for Device {
+    fn clone(&self) -> Device {
+        #[cfg(feature = "leak_check")]
+        {
+            if self.counted {
+                return Device::from_inner(&self.inner);
+            }
+        }
+        Device::derived(&self.inner)
+    }
+}
+
+#[cfg(feature = "leak_check")]
+impl Drop for Device {
+    fn drop(&mut self) {
+        if self.counted {
+            self.inner.registry.handle_dropped();
+        }
+    }
 }
 #[derive(Debug)]
 pub struct Bo {
     ptr: *mut ffi::gbm_bo,
+    dev: Arc<DeviceInner>,
 }
+// User data is required to be `Send`, and everything else that reaches
+// the backend goes through the device lock.
+unsafe impl Send for Bo {}
 #[derive(Debug)]
 pub struct Surface {
     ptr: *mut ffi::gbm_surface,
+    dev: Arc<DeviceInner>,
+    format: BoFormat,
+    damage: damage::DamageHistory,
 }
+unsafe impl Send for Surface {}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum BoFormat {
     XRGB8888,
     ARGB8888,
-    // FourCC(FourCC)
+    FourCC(FourCC)
 }
 impl BoFormat {
+    #[cfg(not(feature = "minigbm"))]
     fn as_u32(&self) -> u32 {
         match *self {
             BoFormat::XRGB8888 => ffi::gbm_bo_format::GBM_BO_FORMAT_XRGB8888 as u32,
             BoFormat::ARGB8888 => ffi::gbm_bo_format::GBM_BO_FORMAT_ARGB8888 as u32,
-            // BoFormat::FourCC(fcc) => fcc as u32
+            BoFormat::FourCC(fcc) => fcc as u32
+        }
+    }
+    // minigbm only understands fourcc codes, not the legacy enum.
+    #[cfg(feature = "minigbm")]
+    fn as_u32(&self) -> u32 {
+        match *self {
+            BoFormat::XRGB8888 => FourCC::XRGB8888 as u32,
+            BoFormat::ARGB8888 => FourCC::ARGB8888 as u32,
+            BoFormat::FourCC(fcc) => fcc as u32
+        }
+    }
+    /// The fourcc code of the format.  The legacy `XRGB8888` and
+    /// `ARGB8888` values map to the fourcc codes of the same name.
+    pub fn fourcc(&self) -> FourCC {
+        match *self {
+            BoFormat::XRGB8888 => FourCC::XRGB8888,
+            BoFormat::ARGB8888 => FourCC::ARGB8888,
+            BoFormat::FourCC(fcc) => fcc,
         }
     }
     fn from_u32(u: u32) -> Option<BoFormat> {
@@ -80,58 +240,52 @@ impl BoFormat {
         } else if u == ffi::gbm_bo_format::GBM_BO_FORMAT_ARGB8888 as u32 {
             Some(BoFormat::ARGB8888)
         } else {
-            // TODO: Add other formats. FourCC formats should be supported
-            None
+            FourCC::from_u32(u).map(BoFormat::FourCC)
         }
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct BoFlags {
-    flags: ffi::gbm_bo_flags,
-}
-impl BoFlags {
-    pub fn new() -> BoFlags {
-        BoFlags {
-            flags: ffi::gbm_bo_flags::empty()
+impl Device {
+    unsafe fn from_ptr(ptr: *mut ffi::gbm_device) -> Option<Device> {
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Device::from_inner(&Arc::new(DeviceInner {
+                ptr,
+                lock: DeviceLock::new(),
+                user_data: Mutex::new(HashMap::new()),
+                #[cfg(feature = "leak_check")]
+                registry: leak::Registry::new(),
+            })))
         }
     }
-    pub fn scanout(&mut self, v: bool) -> &mut BoFlags {
-        self.set(ffi::GBM_BO_USE_SCANOUT, v)
-    }
-    pub fn cursor(&mut self, v: bool) -> &mut BoFlags {
-        self.set(ffi::GBM_BO_USE_CURSOR, v)
-    }
-    pub fn rendering(&mut self, v: bool) -> &mut BoFlags {
-        self.set(ffi::GBM_BO_USE_RENDERING, v)
-    }
-    pub fn write(&mut self, v: bool) -> &mut BoFlags {
-        self.set(ffi::GBM_BO_USE_WRITE, v)
-    }
-    pub fn linear(&mut self, v: bool) -> &mut BoFlags {
-        self.set(ffi::GBM_BO_USE_LINEAR, v)
-    }
 
-    fn as_u32(&self) -> u32 {
-        self.flags.bits()
-    }
-    fn set(&mut self, flag: ffi::gbm_bo_flags, v: bool) -> &mut BoFlags {
-        if v { self.flags.insert(flag) } else { self.flags.remove(flag) }
-        self
+    fn from_inner(inner: &Arc<DeviceInner>) -> Device {
+        #[cfg(feature = "leak_check")]
+        inner.registry.handle_created();
+        Device {
+            inner: inner.clone(),
+            #[cfg(feature = "leak_check")]
+            counted: true,
+        }
     }
-}
 
-impl Device {
-    unsafe fn from_ptr(ptr: *mut ffi::gbm_device) -> Option<Device> {
-        if ptr.is_null() { None } else { Some(Device { ptr: ptr }) }
+    /// A handle for an object's device, not counted as a user handle.
+    fn derived(inner: &Arc<DeviceInner>) -> Device {
+        Device {
+            inner: inner.clone(),
+            #[cfg(feature = "leak_check")]
+            counted: false,
+        }
     }
+    /// The raw device.  Calls made through it bypass the device lock.
     pub fn as_ptr(&mut self) -> *mut ffi::gbm_device {
-        self.ptr
+        self.inner.ptr
     }
 
     #[cfg(feature = "egl_platform")]
     pub fn as_egl_display(&self) -> egl::EGLNativeDisplayType {
-        self.ptr as *mut _
+        self.inner.ptr as *mut _
     }
 
     pub fn create(fd: RawFd) -> Result<Device, Error> {
@@ -144,12 +298,12 @@ impl Device {
     }
 
     pub fn get_fd(&self) -> RawFd {
-        unsafe { ffi::gbm_device_get_fd(self.ptr) }
+        unsafe { ffi::gbm_device_get_fd(self.inner.ptr) }
     }
 
     pub fn get_backend_name_cstr(&self) -> &'static CStr {
         unsafe {
-            CStr::from_ptr(ffi::gbm_device_get_backend_name(self.ptr))
+            CStr::from_ptr(ffi::gbm_device_get_backend_name(self.inner.ptr))
         }
     }
 
@@ -158,87 +312,210 @@ impl Device {
     }
     
     pub fn is_format_supported(&self, format: BoFormat, flags: BoFlags) -> bool {
+        let _lock = self.inner.lock();
         unsafe {
-            ffi::gbm_device_is_format_supported(self.ptr, format.as_u32(),
-                                                flags.as_u32()) != 0
+            ffi::gbm_device_is_format_supported(self.inner.ptr, format.as_u32(),
+                                                flags.bits()) != 0
         }
     }
 
-    pub fn surface_create(&mut self,
+    /// Number of planes a buffer of `format` laid out with `modifier`
+    /// uses, or `None` if the backend does not support the pair.
+    pub fn format_modifier_plane_count(&self, format: FourCC,
+                                       modifier: u64) -> Option<u32> {
+        let _lock = self.inner.lock();
+        let n = unsafe {
+            ffi::gbm_device_get_format_modifier_plane_count(self.inner.ptr,
+                                                            format as u32,
+                                                            modifier)
+        };
+        if n <= 0 { None } else { Some(n as u32) }
+    }
+
+    /// Queries every known fourcc against every usage flag and known
+    /// modifier.  This issues a few hundred queries, so it is meant for
+    /// startup logging and bug reports rather than hot paths.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::probe(self)
+    }
+
+    pub fn surface_create(&self,
                           width: u32, height: u32,
                           format: BoFormat, flags: BoFlags) -> Surface
     {
-        unsafe {
-            Surface::from_ptr(ffi::gbm_surface_create(self.ptr,
-                                                      width, height,
-                                                      format.as_u32(),
-                                                      flags.as_u32()))
-                .unwrap()
-        }
+        let _lock = self.inner.lock();
+        let op = trace::surface_create(width, height, format, flags);
+        ffi::clear_errno();
+        let ptr = unsafe {
+            ffi::gbm_surface_create(self.inner.ptr, width, height, format.as_u32(),
+                                    flags.bits())
+        };
+        let err = if ptr.is_null() { fallback::take_errno() } else { None };
+        let surface = unsafe { Surface::from_ptr(ptr, &self.inner, format) }.ok_or(err);
+        trace::surface_created(&op, surface.as_ref());
+        surface.unwrap()
     }
 
-    pub fn bo_create(&mut self,
+    /// Panics if the allocation fails; see `try_bo_create`.
+    pub fn bo_create(&self,
                      width: u32, height: u32,
                      format: BoFormat, flags: BoFlags) -> Bo
     {
-        unsafe {
-            Bo::from_ptr(ffi::gbm_bo_create(self.ptr,
-                                            width, height,
-                                            format.as_u32(),
-                                            flags.as_u32()))
-                .unwrap()
-        }
+        self.try_bo_create(width, height, format, flags).unwrap()
+    }
+
+    pub fn try_bo_create(&self,
+                         width: u32, height: u32,
+                         format: BoFormat, flags: BoFlags) -> Result<Bo, Error>
+    {
+        self.create_bo(width, height, format, &[], flags).map_err(|_| Error::BoCreation)
+    }
+
+    /// Allocates with one of `modifiers`, chosen by the driver.  An empty
+    /// list falls back to `try_bo_create`.
+    ///
+    /// `gbm_bo_create_with_modifiers` takes no usage, so `flags` is
+    /// ignored unless the `modifiers2` feature is enabled.
+    pub fn bo_create_with_modifiers(&self,
+                                    width: u32, height: u32, format: BoFormat,
+                                    modifiers: &[u64], flags: BoFlags)
+                                    -> Result<Bo, Error>
+    {
+        self.create_bo(width, height, format, modifiers, flags).map_err(|_| Error::BoCreation)
+    }
+
+    /// Allocates with or without modifiers, failing with the errno libgbm
+    /// left behind, if it set one.
+    fn create_bo(&self, width: u32, height: u32, format: BoFormat,
+                 modifiers: &[u64], flags: BoFlags) -> Result<Bo, Option<io::Error>>
+    {
+        let _lock = self.inner.lock();
+        let op = trace::bo_create(&trace::BoCreate {
+            width,
+            height,
+            format,
+            modifiers,
+            flags,
+        });
+        ffi::clear_errno();
+        let ptr = unsafe {
+            if modifiers.is_empty() {
+                ffi::gbm_bo_create(self.inner.ptr, width, height, format.as_u32(),
+                                   flags.bits())
+            } else {
+                Device::create_bo_with_modifiers(self.inner.ptr, width, height, format,
+                                                 modifiers, flags)
+            }
+        };
+        // Before tracing or unlocking can clobber it.
+        let err = if ptr.is_null() { fallback::take_errno() } else { None };
+        let bo = unsafe { Bo::from_ptr(ptr, &self.inner) }.ok_or(err);
+        trace::bo_created(&op, bo.as_ref());
+        bo
+    }
+
+    #[cfg(feature = "modifiers2")]
+    unsafe fn create_bo_with_modifiers(ptr: *mut ffi::gbm_device, width: u32, height: u32,
+                                       format: BoFormat, modifiers: &[u64],
+                                       flags: BoFlags) -> *mut ffi::gbm_bo {
+        ffi::gbm_bo_create_with_modifiers2(ptr, width, height, format.as_u32(),
+                                           modifiers.as_ptr(), modifiers.len() as u32,
+                                           flags.bits())
+    }
+
+    #[cfg(not(feature = "modifiers2"))]
+    unsafe fn create_bo_with_modifiers(ptr: *mut ffi::gbm_device, width: u32, height: u32,
+                                       format: BoFormat, modifiers: &[u64],
+                                       _flags: BoFlags) -> *mut ffi::gbm_bo {
+        ffi::gbm_bo_create_with_modifiers(ptr, width, height, format.as_u32(),
+                                          modifiers.as_ptr(), modifiers.len() as u32)
     }
 
     pub fn bo_import(&self, bo: BoImport, usage: BoFlags) -> Result<Bo, ()>
     {
-        let bo = unsafe { match bo {
+        let _lock = self.inner.lock();
+        let op = trace::bo_import(bo.kind(), usage);
+        ffi::clear_errno();
+        let ptr = unsafe { match bo {
             BoImport::Fd{ fd, width, height, stride, format } => {
                 let mut dmabuf = ffi::gbm_import_fd_data {
-                    fd: fd, width: width, height: height,
-                    stride: stride, format: format.as_u32()
+                    fd: fd.as_raw_fd(), width, height,
+                    stride, format: format.as_u32()
                 };
-                ffi::gbm_bo_import(self.ptr, ffi::GBM_BO_IMPORT_FD,
+                ffi::gbm_bo_import(self.inner.ptr, ffi::GBM_BO_IMPORT_FD,
                                    &mut dmabuf as *mut _ as *mut _,
-                                   usage.as_u32())
+                                   usage.bits())
             }
-            #[cfg(feature = "wl_server")]
+            BoImport::Dmabuf(dmabuf) => {
+                dmabuf::import(self.inner.ptr, dmabuf, usage)
+            }
+            // minigbm only implements the fd import types.
+            #[cfg(all(feature = "wl_server", feature = "minigbm"))]
+            BoImport::WlBuffer(_) => ptr::null_mut(),
+            #[cfg(all(feature = "egl_platform", feature = "minigbm"))]
+            BoImport::EglImage(_) => ptr::null_mut(),
+            #[cfg(all(feature = "wl_server", not(feature = "minigbm")))]
             BoImport::WlBuffer(wl_buffer) => {
-                ffi::gbm_bo_import(self.ptr, ffi::GBM_BO_IMPORT_WL_BUFFER,
-                              wl_buffer.ptr() as *mut c_void, usage.as_u32())
+                ffi::gbm_bo_import(self.inner.ptr, ffi::GBM_BO_IMPORT_WL_BUFFER,
+                              wl_buffer.ptr() as *mut c_void, usage.bits())
             }
-            #[cfg(feature = "egl_platform")]
+            #[cfg(all(feature = "egl_platform", not(feature = "minigbm")))]
             BoImport::EglImage(egl_image) => {
-                ffi::gbm_bo_import(self.ptr, ffi::GBM_BO_IMPORT_EGL_IMAGE,
-                              egl_image, usage.as_u32())
+                ffi::gbm_bo_import(self.inner.ptr, ffi::GBM_BO_IMPORT_EGL_IMAGE,
+                              egl_image.as_raw(), usage.bits())
             }
         } };
-        if bo.is_null() {
-            Err(())
-        } else {
-            Ok(Bo { ptr: bo })
-        }
+        let err = if ptr.is_null() { fallback::take_errno() } else { None };
+        let bo = unsafe { Bo::from_ptr(ptr, &self.inner) }.ok_or(err);
+        trace::bo_imported(&op, bo.as_ref());
+        bo.map_err(|_| ())
     }
 
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        unsafe { ffi::gbm_device_destroy(self.ptr) }
-    }
-}
-
-pub enum BoImport {
-    Fd { fd: RawFd, width: u32, height: u32, stride: u32, format: BoFormat },
+/// A buffer to import.  Borrowed resources only need to stay valid for
+/// the duration of `Device::bo_import`.
+pub enum BoImport<'a> {
+    Fd { fd: BorrowedFd<'a>, width: u32, height: u32, stride: u32, format: BoFormat },
+    /// Any number of planes, with an explicit modifier.
+    Dmabuf(&'a Dmabuf),
     #[cfg(feature = "wl_server")]
     WlBuffer(WlBuffer),
     #[cfg(feature = "egl_platform")]
-    EglImage(EGLImageKHR),
+    EglImage(&'a egl_platform::EglImage),
+}
+
+impl<'a> BoImport<'a> {
+    fn kind(&self) -> &'static str {
+        match *self {
+            BoImport::Fd { .. } => "fd",
+            BoImport::Dmabuf(_) => "dmabuf",
+            #[cfg(feature = "wl_server")]
+            BoImport::WlBuffer(_) => "wl_buffer",
+            #[cfg(feature = "egl_platform")]
+            BoImport::EglImage(_) => "egl_image",
+        }
+    }
 }
 
 impl Bo {
-    unsafe fn from_ptr(ptr: *mut ffi::gbm_bo) -> Option<Bo> {
-        if ptr.is_null() { None } else { Some(Bo { ptr: ptr }) }
+    unsafe fn from_ptr(ptr: *mut ffi::gbm_bo, dev: &Arc<DeviceInner>) -> Option<Bo> {
+        if ptr.is_null() {
+            return None;
+        }
+        #[cfg(feature = "leak_check")]
+        dev.registry.add(leak::ObjectKind::Bo, ptr);
+        Some(Bo { ptr, dev: dev.clone() })
+    }
+
+    /// Gives up ownership of the `gbm_bo` without destroying it.
+    fn into_raw(self) -> *mut ffi::gbm_bo {
+        let ptr = self.ptr;
+        #[cfg(feature = "leak_check")]
+        self.dev.registry.remove(ptr);
+        unsafe { drop(ptr::read(&self.dev)) };
+        forget(self);
+        ptr
     }
 
     #[cfg(feature = "egl_platform")]
@@ -261,12 +538,12 @@ impl Bo {
         }).unwrap()
     }
     pub fn get_device(&self) -> Device {
-        unsafe {
-            Device::from_ptr(ffi::gbm_bo_get_device(self.ptr))
-                .unwrap()
-        }
+        Device::derived(&self.dev)
     }
 
+    /// The handle read as a GEM handle.  This is only meaningful for
+    /// backends that hand out GEM handles; see `handle` for a version
+    /// that respects the backend.
     pub fn get_handle(&self) -> Handle {
         unsafe {
             ffi::gbm_bo_get_handle(self.ptr).u32()
@@ -274,83 +551,155 @@ impl Bo {
     }
     
     pub fn get_fd(&self) -> RawFd {
+        let _lock = self.dev.lock();
         unsafe {
             ffi::gbm_bo_get_fd(self.ptr)
         }
     }
 
-    /// Set's user data.  Internally this is done by creating a
-    /// `Box<Box<T>>` containing your data then setting up a callback
-    /// that cast's the pointer given to use by libgbm back to a Box
-    /// so that it will be dropped by Rust.
-    ///
-    /// This does not check if user data was already set, and will
-    /// leak the already set user data.
-    pub fn set_user_data<T:Any>(&mut self, data: T) {
-        // TODO: This may involve some rework.  There might be a good
-        // reason to instead point to some struct that contains the
-        // `Box<Any> ` and some other useful data. (Perhaps a safe way to
-        // check if the data is from Rust at all.)
-        //
-        #[repr(C)]
-        struct _user_data {
-            // Compare this to some global initialised pointer to
-            // validate that this is rust data
-            is_rust_check: *const u32,
-            user_data: Box<Any>,
-        };
-        
-        extern fn destroy_user_data(_bo: *mut ffi::gbm_bo, ptr: *mut c_void) {
-            unsafe {
-                let _b: Box<Box<Any>> = Box::from_raw(ptr as *mut _);
-                // Rust will magically Drop for us!
-            }
+    /// Exports `plane` as a new dmabuf fd, owned by the caller.
+    pub fn get_fd_for_plane(&self, plane: u32) -> RawFd {
+        let _lock = self.dev.lock();
+        unsafe {
+            ffi::gbm_bo_get_fd_for_plane(self.ptr, plane as libc::c_int)
         }
+    }
+
+    /// The format modifier, `ffi::DRM_FORMAT_MOD_INVALID` if the backend
+    /// does not know it.
+    pub fn get_modifier(&self) -> u64 {
+        let _lock = self.dev.lock();
+        unsafe { ffi::gbm_bo_get_modifier(self.ptr) }
+    }
+    pub fn get_offset(&self, plane: u32) -> u32 {
+        let _lock = self.dev.lock();
+        unsafe { ffi::gbm_bo_get_offset(self.ptr, plane as libc::c_int) }
+    }
+    pub fn get_stride_for_plane(&self, plane: u32) -> u32 {
+        let _lock = self.dev.lock();
+        unsafe { ffi::gbm_bo_get_stride_for_plane(self.ptr, plane as libc::c_int) }
+    }
 
-        let b: Box<Box<Any>> = Box::new(Box::new(data) as Box<Any>);
+    /// Set's user data, returning whatever Rust data was set before.
+    ///
+    /// Internally this is done by boxing a `UserData` that holds your
+    /// data, recording its address with the device, then setting up a
+    /// callback that casts the pointer given to us by libgbm back to a
+    /// Box so that it will be dropped by Rust.
+    ///
+    /// Fails with `Error::ForeignUserData` if the user data was set by C
+    /// code, as overwriting it would leak it behind its owner's back.
+    pub fn set_user_data<T:Any + Send>(&mut self, data: T)
+                                       -> Result<Option<Box<dyn Any + Send>>, Error>
+    {
+        let old = self.take_user_data()?;
+        let b = Box::new(UserData {
+            bo: self.ptr as usize,
+            dev: Arc::downgrade(&self.dev),
+            user_data: Box::new(data),
+        });
         let ptr = Box::into_raw(b) as *mut c_void;
-        let callback = destroy_user_data as ffi::BoCallback;
+        lock_user_data(&self.dev).insert(self.ptr as usize, ptr as usize);
+        unsafe {
+            ffi::gbm_bo_set_user_data(self.ptr, ptr, Some(destroy_user_data))
+        }
+        Ok(old)
+    }
+
+    /// Removes the user data without dropping it.
+    pub fn take_user_data(&mut self) -> Result<Option<Box<dyn Any + Send>>, Error> {
+        let data = match self.rust_user_data()? {
+            None => return Ok(None),
+            Some(data) => data,
+        };
         unsafe {
-            ffi::gbm_bo_set_user_data(self.ptr, ptr, callback)
+            ffi::gbm_bo_set_user_data(self.ptr, ptr::null_mut(), None);
+            let b: Box<UserData> = Box::from_raw(data);
+            b.unregister();
+            Ok(Some(b.user_data))
         }
     }
 
+    /// Drops the user data, if any.
+    pub fn clear_user_data(&mut self) -> Result<(), Error> {
+        self.take_user_data().map(|_| ())
+    }
+
+    /// True if any user data is set, including data set by C code.
     pub fn has_user_data(&self) -> bool {
         unsafe { !ffi::gbm_bo_get_user_data(self.ptr).is_null() }
     }
+
+    /// True if user data is set but was not set by this crate.
+    pub fn has_foreign_user_data(&self) -> bool {
+        self.rust_user_data() == Err(Error::ForeignUserData)
+    }
     
     /// Internally this is using Any::downcase_ref to check the
     /// underlying type.
     ///
-    /// This will fail either because there is no
-    /// data or the data is not the correct type.
+    /// This will fail either because there is no Rust data or the
+    /// data is not the correct type.
     /// (The former can be checked using `has_user_data`)
     pub fn get_user_data<'a, T: Any>(&'a self) -> Option<&'a T> {
-        unsafe {
-            let ptr = ffi::gbm_bo_get_user_data(self.ptr) as *mut Box<Any>;
-            if ptr.is_null() {
-                return None
-            } else {
-                let r: &'a Box<Any> = &(*ptr);
-                r.downcast_ref()
-            }            
+        match self.rust_user_data() {
+            Ok(Some(ptr)) => unsafe { (*ptr).user_data.downcast_ref() },
+            _ => None,
         }
     }
     pub fn get_user_data_mut<'a, T: Any>(&'a mut self) -> Option<&'a mut T> {
-        unsafe {
-            let ptr = ffi::gbm_bo_get_user_data(self.ptr) as *mut Box<Any>;
-            if ptr.is_null() {
-                return None
-            } else {
-                let r: &'a mut Box<Any> = &mut (*ptr);
-                r.downcast_mut()
-            }            
+        match self.rust_user_data() {
+            Ok(Some(ptr)) => unsafe { (*ptr).user_data.downcast_mut() },
+            _ => None,
         }
     }
+
+    /// The user data pointer if it was set by `set_user_data`.
+    fn rust_user_data(&self) -> Result<Option<*mut UserData>, Error> {
+        let ptr = unsafe { ffi::gbm_bo_get_user_data(self.ptr) as *mut UserData };
+        if ptr.is_null() {
+            Ok(None)
+        } else if lock_user_data(&self.dev).get(&(self.ptr as usize)) == Some(&(ptr as usize)) {
+            Ok(Some(ptr))
+        } else {
+            Err(Error::ForeignUserData)
+        }
+    }
+}
+
+fn lock_user_data(dev: &DeviceInner) -> MutexGuard<'_, HashMap<usize, usize>> {
+    dev.user_data.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct UserData {
+    bo: usize,
+    dev: Weak<DeviceInner>,
+    user_data: Box<dyn Any + Send>,
+}
+
+impl UserData {
+    /// Forgets the address of `self`, once libgbm no longer holds it.
+    fn unregister(&self) {
+        if let Some(dev) = self.dev.upgrade() {
+            let mut map = lock_user_data(&dev);
+            if map.get(&self.bo) == Some(&(self as *const UserData as usize)) {
+                map.remove(&self.bo);
+            }
+        }
+    }
+}
+
+extern "C" fn destroy_user_data(_bo: *mut ffi::gbm_bo, ptr: *mut c_void) {
+    callback::guard(|| unsafe {
+        let b: Box<UserData> = Box::from_raw(ptr as *mut _);
+        b.unregister();
+        // Rust will magically Drop for us!
+    })
 }
 
 impl io::Write for Bo {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _lock = self.dev.lock();
         unsafe {
             let ptr = buf.as_ptr() as *const c_void;
             if ffi::gbm_bo_write(self.ptr, ptr, buf.len()) == -1 {
@@ -367,16 +716,44 @@ impl io::Write for Bo {
 
 impl Drop for Bo {
     fn drop(&mut self) {
-        unsafe {
-            ffi::gbm_bo_destroy(self.ptr)
+        trace::bo_destroy(self.ptr);
+        #[cfg(feature = "leak_check")]
+        self.dev.registry.remove(self.ptr);
+        {
+            let _lock = self.dev.lock();
+            unsafe {
+                ffi::gbm_bo_destroy(self.ptr)
+            }
         }
+        callback::resume()
     }
 }
 
 
 impl Surface {
-    unsafe fn from_ptr(ptr: *mut ffi::gbm_surface) -> Option<Surface> {
-        if ptr.is_null() { None } else { Some(Surface { ptr: ptr }) }
+    unsafe fn from_ptr(ptr: *mut ffi::gbm_surface, dev: &Arc<DeviceInner>,
+                       format: BoFormat) -> Option<Surface> {
+        if ptr.is_null() {
+            None
+        } else {
+            #[cfg(feature = "leak_check")]
+            dev.registry.add(leak::ObjectKind::Surface, ptr);
+            Some(Surface {
+                ptr,
+                dev: dev.clone(),
+                format,
+                damage: damage::DamageHistory::default(),
+            })
+        }
+    }
+
+    /// The format the surface was created with.
+    pub fn get_format(&self) -> BoFormat {
+        self.format
+    }
+
+    pub fn get_device(&self) -> Device {
+        Device::derived(&self.dev)
     }
 
     #[cfg(feature = "egl_platform")]
@@ -385,23 +762,42 @@ impl Surface {
     }
     
     pub fn needs_lock_front_buffer(&self) -> bool {
+        let _lock = self.dev.lock();
         unsafe { ffi::gbm_surface_needs_lock_front_buffer(self.ptr) != 0 }
     }
     
+    /// Locks the buffer just rendered, ending the frame for damage
+    /// tracking; see `buffer_age`.
     pub fn lock_front_buffer(&mut self) -> Option<Bo> {
-        unsafe {
-            Bo::from_ptr(ffi::gbm_surface_lock_front_buffer(self.ptr))
-        }
+        let _lock = self.dev.lock();
+        let op = trace::lock_front_buffer(self.ptr);
+        let bo = unsafe {
+            Bo::from_ptr(ffi::gbm_surface_lock_front_buffer(self.ptr), &self.dev)
+        };
+        let bo = match bo {
+            Some(bo) => bo,
+            None => {
+                trace::locked(&op, None);
+                return None;
+            }
+        };
+        #[cfg(feature = "leak_check")]
+        self.dev.registry.locked(bo.ptr);
+        self.damage.on_lock(&bo);
+        trace::locked(&op, Some((&bo, self.buffer_age(&bo))));
+        Some(bo)
     }
 
     pub fn release_buffer(&mut self, bo: Bo) {
+        let _lock = self.dev.lock();
+        trace::release_buffer(self.ptr, bo.ptr);
         unsafe {
-            ffi::gbm_surface_release_buffer(self.ptr, bo.ptr);
-            forget(bo);
+            ffi::gbm_surface_release_buffer(self.ptr, bo.into_raw());
         }
     }
 
     pub fn has_free_buffers(&self) -> bool {
+        let _lock = self.dev.lock();
         unsafe {
             ffi::gbm_surface_has_free_buffers(self.ptr) != 0
         }
@@ -410,9 +806,18 @@ impl Surface {
 
 impl Drop for Surface {
     fn drop(&mut self) {
-        unsafe {
-            ffi::gbm_surface_destroy(self.ptr)
+        trace::surface_destroy(self.ptr);
+        #[cfg(feature = "leak_check")]
+        self.dev.registry.remove(self.ptr);
+        // Destroys the surface's buffers, running their user data
+        // destructors.
+        {
+            let _lock = self.dev.lock();
+            unsafe {
+                ffi::gbm_surface_destroy(self.ptr)
+            }
         }
+        callback::resume()
     }
 }
 
@@ -0,0 +1,185 @@
+//! CPU mappings of buffer objects.
+//!
+//! Mesa and minigbm disagree on the mapping contract.  Mesa's
+//! `gbm_bo_map` only maps the first plane and may hand back a staging
+//! copy whose stride differs from `Bo::get_stride`.  minigbm maps any
+//! plane through `gbm_bo_map2`, returns the real buffer memory, and only
+//! does so cheaply for buffers allocated with the `SW_*` usage flags.
+//! `BoMapping` hides both behind the same interface; always use its
+//! `stride` rather than the buffer's.
+
+use std::ptr;
+use std::slice;
+
+use libc::c_void;
+
+use {fallback, ffi, trace, Bo, Error};
+
+/// Direction of a mapping.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Transfer {
+    Read,
+    Write,
+    ReadWrite,
+}
+impl Transfer {
+    fn as_u32(&self) -> u32 {
+        match *self {
+            Transfer::Read => ffi::GBM_BO_TRANSFER_READ,
+            Transfer::Write => ffi::GBM_BO_TRANSFER_WRITE,
+            Transfer::ReadWrite => ffi::GBM_BO_TRANSFER_READ_WRITE,
+        }
+    }
+    pub fn readable(&self) -> bool {
+        *self != Transfer::Write
+    }
+    pub fn writable(&self) -> bool {
+        *self != Transfer::Read
+    }
+}
+
+/// A mapped region of a `Bo`, unmapped on drop.
+#[derive(Debug)]
+pub struct BoMapping<'a> {
+    bo: &'a Bo,
+    addr: *mut u8,
+    map_data: *mut c_void,
+    stride: u32,
+    row_bytes: u32,
+    width: u32,
+    height: u32,
+    transfer: Transfer,
+    /// Span of the mapping, closed after unmapping.
+    span: trace::Op,
+}
+
+impl Bo {
+    /// Maps the `width` × `height` rectangle at (`x`, `y`) of the first
+    /// plane.
+    pub fn map(&self, x: u32, y: u32, width: u32, height: u32,
+               transfer: Transfer) -> Result<BoMapping<'_>, Error> {
+        self.map_plane(0, x, y, width, height, transfer)
+    }
+
+    /// Maps every plane whole.  Mesa can only map plane 0, so this fails
+    /// there for multi-planar formats.
+    pub fn map_planes(&self, transfer: Transfer) -> Result<Vec<BoMapping<'_>>, Error> {
+        let (width, height) = (self.get_width(), self.get_height());
+        self.get_format().fourcc().planes().iter().enumerate()
+            .map(|(i, info)| self.map_plane(i as u32, 0, 0, width, info.rows(height), transfer))
+            .collect()
+    }
+
+    /// Maps a rectangle of one plane.  Mesa can only map plane 0 and
+    /// fails for any other plane.
+    pub fn map_plane(&self, plane: u32, x: u32, y: u32, width: u32, height: u32,
+                     transfer: Transfer) -> Result<BoMapping<'_>, Error> {
+        let mut stride = 0;
+        let mut map_data = ptr::null_mut();
+        let _lock = self.dev.lock();
+        let region = trace::MapRegion { plane, x, y, width, height, transfer };
+        let span = trace::map(self.ptr, &region);
+        ffi::clear_errno();
+        let addr = unsafe { raw_map(self.ptr, &region, &mut stride, &mut map_data) };
+        let ok = addr.is_some_and(|a| !a.is_null());
+        let err = if ok { None } else { fallback::take_errno() };
+        trace::mapped(&span, if ok { Ok(stride) } else { Err(&err) });
+        // Only `width` pixels of each row belong to the mapping; the last
+        // row in particular may end right at the end of the buffer.
+        let row_bytes = self.get_format().fourcc().planes().get(plane as usize)
+            .map_or(stride, |info| info.row_bytes(width))
+            .min(stride);
+        match addr {
+            Some(addr) if !addr.is_null() => Ok(BoMapping {
+                bo: self,
+                addr: addr as *mut u8,
+                map_data,
+                stride,
+                row_bytes,
+                width,
+                height,
+                transfer,
+                span,
+            }),
+            _ => Err(Error::BoMap),
+        }
+    }
+}
+
+#[cfg(not(feature = "minigbm"))]
+unsafe fn raw_map(bo: *mut ffi::gbm_bo, r: &trace::MapRegion,
+                  stride: &mut u32, map_data: &mut *mut c_void) -> Option<*mut c_void> {
+    if r.plane != 0 {
+        return None;
+    }
+    Some(ffi::gbm_bo_map(bo, r.x, r.y, r.width, r.height, r.transfer.as_u32(),
+                         stride, map_data))
+}
+
+#[cfg(feature = "minigbm")]
+unsafe fn raw_map(bo: *mut ffi::gbm_bo, r: &trace::MapRegion,
+                  stride: &mut u32, map_data: &mut *mut c_void) -> Option<*mut c_void> {
+    Some(ffi::gbm_bo_map2(bo, r.x, r.y, r.width, r.height, r.transfer.as_u32(),
+                          stride, map_data, r.plane as ::libc::c_int))
+}
+
+impl<'a> BoMapping<'a> {
+    pub fn bo(&self) -> &'a Bo {
+        self.bo
+    }
+    /// Bytes between the starts of two rows of the mapping.
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn transfer(&self) -> Transfer {
+        self.transfer
+    }
+
+    /// The mapped rectangle, starting at its top-left corner.  Rows are
+    /// `stride` bytes apart, but the last one ends after its `width`
+    /// pixels, so the slice is `stride * (height - 1)` plus one row's
+    /// pixel bytes long.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.addr, self.len()) }
+    }
+
+    /// Panics if the mapping was not created with write access.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        assert!(self.transfer.writable(), "mapping is read only");
+        unsafe { slice::from_raw_parts_mut(self.addr, self.len()) }
+    }
+
+    /// The `width` pixels of row `y` of the mapping.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = y as usize * self.stride as usize;
+        &self.as_slice()[start..start + self.row_bytes as usize]
+    }
+
+    pub fn row_mut(&mut self, y: u32) -> &mut [u8] {
+        let start = y as usize * self.stride as usize;
+        let end = start + self.row_bytes as usize;
+        &mut self.as_mut_slice()[start..end]
+    }
+
+    fn len(&self) -> usize {
+        match self.height {
+            0 => 0,
+            h => self.stride as usize * (h as usize - 1) + self.row_bytes as usize,
+        }
+    }
+}
+
+impl<'a> Drop for BoMapping<'a> {
+    fn drop(&mut self) {
+        let _lock = self.bo.dev.lock();
+        trace::unmap(&self.span);
+        unsafe { ffi::gbm_bo_unmap(self.bo.ptr, self.map_data) }
+    }
+}
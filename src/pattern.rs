@@ -0,0 +1,224 @@
+//! Test patterns for display bring-up.
+//!
+//! Patterns are drawn in RGB and converted to the buffer's format, using
+//! BT.601 limited range for YUV formats.  Subsampled chroma is taken from
+//! the top-left pixel it covers.  `fill` draws into any memory laid out
+//! as described by `FourCC::planes`; `Bo::fill_pattern` maps a buffer and
+//! draws into that.
+
+use pixel::{self, Color, Layout};
+use {Bo, Error, FourCC, Transfer};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Pattern {
+    /// SMPTE colour bars: 75% bars, the reverse blue bars, and -I,
+    /// white, +Q and a PLUGE along the bottom.
+    SmpteBars,
+    /// Grey, red, green and blue ramps stacked vertically, for spotting
+    /// banding and swapped channels.
+    Gradients,
+    /// Black and white squares of the given size in pixels.
+    Checkerboard(u32),
+    /// Every component ramps over its full code range: red or Y
+    /// horizontally, green or Cb vertically, blue or Cr diagonally.
+    /// Planar formats thus show one ramp per plane.
+    PlaneRamps,
+    /// Diagonal stripes moving with the frame number, which is also
+    /// shown in binary in the top left corner, most significant bit first.
+    MovingStripes(u64),
+}
+
+/// One plane of an image to draw into.
+#[derive(Debug)]
+pub struct PlaneMut<'a> {
+    pub data: &'a mut [u8],
+    /// Bytes between the starts of two rows.
+    pub stride: usize,
+}
+
+/// One plane of an image to read from.
+#[derive(Copy, Clone, Debug)]
+pub struct PlaneRef<'a> {
+    pub data: &'a [u8],
+    pub stride: usize,
+}
+
+const BLACK: (f32, f32, f32) = (0.0, 0.0, 0.0);
+const WHITE: (f32, f32, f32) = (1.0, 1.0, 1.0);
+
+fn smpte(fx: f32, fy: f32) -> (f32, f32, f32) {
+    const TOP: [(f32, f32, f32); 7] = [
+        (0.75, 0.75, 0.75), (0.75, 0.75, 0.0), (0.0, 0.75, 0.75), (0.0, 0.75, 0.0),
+        (0.75, 0.0, 0.75), (0.75, 0.0, 0.0), (0.0, 0.0, 0.75),
+    ];
+    const MIDDLE: [(f32, f32, f32); 7] = [
+        (0.0, 0.0, 0.75), BLACK, (0.75, 0.0, 0.75), BLACK,
+        (0.0, 0.75, 0.75), BLACK, (0.75, 0.75, 0.75),
+    ];
+    // In units of one bar.
+    let bar = fx * 7.0;
+    if fy < 2.0 / 3.0 {
+        TOP[(bar as usize).min(6)]
+    } else if fy < 0.75 {
+        MIDDLE[(bar as usize).min(6)]
+    } else if bar < 1.25 {
+        (0.0, 0.129, 0.294) // -I
+    } else if bar < 2.5 {
+        WHITE
+    } else if bar < 3.75 {
+        (0.196, 0.0, 0.416) // +Q
+    } else if bar < 5.0 + 2.0 / 3.0 {
+        // Blacker than black can't be shown, so the PLUGE starts at black.
+        BLACK
+    } else if bar < 6.0 {
+        (0.04, 0.04, 0.04)
+    } else {
+        BLACK
+    }
+}
+
+fn sample(pattern: Pattern, yuv: bool, x: u32, y: u32, width: u32, height: u32) -> Color {
+    let fx = x as f32 / width.max(1) as f32;
+    let fy = y as f32 / height.max(1) as f32;
+    let ramp = |v: u32, len: u32| v as f32 / (len.max(2) - 1) as f32;
+    let (r, g, b) = match pattern {
+        Pattern::SmpteBars => smpte(fx, fy),
+        Pattern::Gradients => {
+            let t = ramp(x, width);
+            match (fy * 4.0) as u32 {
+                0 => (t, t, t),
+                1 => (t, 0.0, 0.0),
+                2 => (0.0, t, 0.0),
+                _ => (0.0, 0.0, t),
+            }
+        }
+        Pattern::Checkerboard(size) => {
+            let size = size.max(1);
+            if (x / size + y / size).is_multiple_of(2) { WHITE } else { BLACK }
+        }
+        Pattern::PlaneRamps => {
+            let (a, b, c) = (ramp(x, width), ramp(y, height), ramp(x + y, width + height - 1));
+            return if yuv { Color::Yuv(a, b, c) } else { Color::Rgb(a, b, c, 1.0) };
+        }
+        Pattern::MovingStripes(frame) => {
+            const BLOCK: u32 = 8;
+            const BITS: u32 = 32;
+            if y < BLOCK && x < BITS * BLOCK {
+                let bit = BITS - 1 - x / BLOCK;
+                if (frame >> bit) & 1 == 1 { WHITE } else { (0.2, 0.2, 0.2) }
+            } else {
+                const COLORS: [(f32, f32, f32); 6] = [
+                    (1.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.0, 0.0, 1.0),
+                    (1.0, 1.0, 0.0), (0.0, 1.0, 1.0), (1.0, 0.0, 1.0),
+                ];
+                let pos = (x as u64 + y as u64 + frame.wrapping_mul(4)) / 32;
+                COLORS[(pos % COLORS.len() as u64) as usize]
+            }
+        }
+    };
+    Color::Rgb(r, g, b, 1.0)
+}
+
+/// Draws `pattern` into the planes of a `width` × `height` image of
+/// `format`.  Fails with `Error::PlaneLayout` if the planes don't match
+/// the format's layout.
+pub fn fill(format: FourCC, width: u32, height: u32, planes: &mut [PlaneMut],
+            pattern: Pattern) -> Result<(), Error> {
+    let layout = Layout::of(format).ok_or(Error::PlaneLayout)?;
+    pixel::check_layout(format, width, height,
+                        planes.iter().map(|p| (p.data.len(), p.stride)))?;
+    let yuv = format.is_yuv();
+    for y in 0..height {
+        for x in 0..width {
+            pixel::write(&layout, planes, x, y, sample(pattern, yuv, x, y, width, height));
+        }
+    }
+    Ok(())
+}
+
+impl Bo {
+    /// Maps every plane and draws `pattern` into the buffer.  Mesa can
+    /// only map the first plane, so there this only works for single
+    /// plane formats.
+    pub fn fill_pattern(&self, pattern: Pattern) -> Result<(), Error> {
+        let format = self.get_format().fourcc();
+        let mut maps = self.map_planes(Transfer::Write)?;
+        let mut planes: Vec<PlaneMut> = maps.iter_mut().map(|m| PlaneMut {
+            stride: m.stride() as usize,
+            data: m.as_mut_slice(),
+        }).collect();
+        fill(format, self.get_width(), self.get_height(), &mut planes, pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: [Pattern; 5] = [
+        Pattern::SmpteBars, Pattern::Gradients, Pattern::Checkerboard(4),
+        Pattern::PlaneRamps, Pattern::MovingStripes(5),
+    ];
+
+    fn fill_bufs(format: FourCC, width: u32, height: u32, bufs: &mut [(Vec<u8>, usize)],
+                 pattern: Pattern) -> Result<(), Error> {
+        fill(format, width, height, &mut pixel::planes_mut(bufs), pattern)
+    }
+
+    #[test]
+    fn every_format_and_pattern() {
+        for &format in FourCC::ALL {
+            if Layout::of(format).is_none() {
+                continue;
+            }
+            for &pattern in &PATTERNS {
+                // Odd sizes, so subsampled chroma has partial blocks.
+                let mut bufs = pixel::test_planes(format, 33, 17, 3, 0xa5);
+                fill_bufs(format, 33, 17, &mut bufs, pattern).unwrap();
+                // Row padding is left alone.
+                for &(ref data, stride) in &bufs {
+                    assert_eq!(&data[stride - 3..stride], &[0xa5; 3], "{:?}", format);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checkerboard() {
+        let mut bufs = pixel::test_planes(FourCC::XRGB8888, 8, 8, 0, 0xa5);
+        fill_bufs(FourCC::XRGB8888, 8, 8, &mut bufs, Pattern::Checkerboard(4)).unwrap();
+        let px = |x: usize, y: usize| &bufs[0].0[y * 32 + x * 4..y * 32 + x * 4 + 3];
+        assert_eq!(px(0, 0), &[0xff; 3]);
+        assert_eq!(px(4, 0), &[0; 3]);
+        assert_eq!(px(4, 4), &[0xff; 3]);
+        assert_eq!(px(3, 7), &[0; 3]);
+    }
+
+    #[test]
+    fn plane_ramps_p010() {
+        let mut bufs = pixel::test_planes(FourCC::P010, 4, 2, 0, 0xa5);
+        fill_bufs(FourCC::P010, 4, 2, &mut bufs, Pattern::PlaneRamps).unwrap();
+        let sample = |plane: usize, i: usize| {
+            u16::from_le_bytes([bufs[plane].0[i * 2], bufs[plane].0[i * 2 + 1]])
+        };
+        // Y ramps from 0 to the full 10 bit range, in the high bits.
+        assert_eq!(sample(0, 0), 0);
+        assert_eq!(sample(0, 3), 0x3ff << 6);
+        assert!((0..4).all(|i| sample(0, i) & 0x3f == 0));
+        // Cb is taken from the top row.
+        assert_eq!(sample(1, 0), 0);
+    }
+
+    #[test]
+    fn bad_layouts_rejected() {
+        let mut bufs = pixel::test_planes(FourCC::NV12, 4, 4, 0, 0xa5);
+        bufs.pop();
+        assert!(fill_bufs(FourCC::NV12, 4, 4, &mut bufs, Pattern::SmpteBars).is_err());
+        let mut bufs = pixel::test_planes(FourCC::XRGB8888, 4, 4, 0, 0xa5);
+        bufs[0].0.pop();
+        assert!(fill_bufs(FourCC::XRGB8888, 4, 4, &mut bufs, Pattern::SmpteBars).is_err());
+        let mut bufs = pixel::test_planes(FourCC::XRGB8888, 4, 4, 0, 0xa5);
+        bufs[0].1 = 15;
+        assert!(fill_bufs(FourCC::XRGB8888, 4, 4, &mut bufs, Pattern::SmpteBars).is_err());
+    }
+}
@@ -0,0 +1,463 @@
+//! Reading and writing single pixels of any `FourCC`.
+//!
+//! YUV formats use BT.601 limited range.  Writing a pixel of a format
+//! with subsampled chroma only stores chroma for the top-left pixel of
+//! each block.
+
+use ffi::FourCC::*;
+use pattern::{PlaneMut, PlaneRef};
+use {Error, FourCC};
+
+/// Components in 0..=1.  `Yuv` is stored as is, over the full code range.
+#[derive(Copy, Clone, Debug)]
+pub enum Color {
+    Rgb(f32, f32, f32, f32),
+    Yuv(f32, f32, f32),
+}
+
+/// How a format stores its pixels.
+#[derive(Clone, Debug)]
+pub enum Layout {
+    /// Channels most significant first with their widths in bits, and
+    /// bytes per pixel.
+    Rgb(Vec<(u8, u32)>, usize),
+    /// Byte positions of Y0, Cb, Y1 and Cr in each pixel pair.
+    Packed422(usize, usize, usize, usize),
+    Ayuv,
+    /// Y plane and interleaved chroma plane, with bits per sample and
+    /// bytes per sample.
+    SemiPlanar { bits: u32, size: usize, cb_first: bool, hsub: u32, vsub: u32 },
+    Planar { cb_first: bool, hsub: u32, vsub: u32 },
+}
+
+impl Layout {
+    pub fn of(format: FourCC) -> Option<Layout> {
+        let chroma = || format.planes().get(1).map(|p| (p.hsub, p.vsub)).unwrap_or((1, 1));
+        let cb_first = !matches!(format,
+                                 NV21 | NV61 | YVU410 | YVU411 | YVU420 | YVU422 | YVU444);
+        Some(match format {
+            YUYV => Layout::Packed422(0, 1, 2, 3),
+            YVYU => Layout::Packed422(0, 3, 2, 1),
+            UYVY => Layout::Packed422(1, 0, 3, 2),
+            VYUY => Layout::Packed422(1, 2, 3, 0),
+            AYUV => Layout::Ayuv,
+            NV12 | NV21 | NV16 | NV61 | P010 => {
+                let (bits, size) = if format == P010 { (10, 2) } else { (8, 1) };
+                let (hsub, vsub) = chroma();
+                Layout::SemiPlanar { bits, size, cb_first, hsub, vsub }
+            }
+            _ if format.is_yuv() => {
+                let (hsub, vsub) = chroma();
+                Layout::Planar { cb_first, hsub, vsub }
+            }
+            _ => Layout::Rgb(rgb_channels(format)?, format.planes()[0].cpp as usize),
+        })
+    }
+}
+
+/// Channels of a packed RGB format, derived from its name.
+fn rgb_channels(format: FourCC) -> Option<Vec<(u8, u32)>> {
+    let name = format.name();
+    let split = name.find(|c: char| c.is_ascii_digit())?;
+    let bits: &[u32] = match &name[split..] {
+        "8" => &[8],
+        "332" => &[3, 3, 2],
+        "233" => &[2, 3, 3],
+        "4444" => &[4, 4, 4, 4],
+        "1555" => &[1, 5, 5, 5],
+        "5551" => &[5, 5, 5, 1],
+        "565" => &[5, 6, 5],
+        "888" => &[8, 8, 8],
+        "8888" => &[8, 8, 8, 8],
+        "2101010" => &[2, 10, 10, 10],
+        "1010102" => &[10, 10, 10, 2],
+        _ => return None,
+    };
+    Some(name[..split].bytes().zip(bits.iter().cloned()).collect())
+}
+
+/// Checks that planes given as (length, stride) can hold a `width` ×
+/// `height` image of `format`.
+pub fn check_layout<I>(format: FourCC, width: u32, height: u32, planes: I) -> Result<(), Error>
+    where I: ExactSizeIterator<Item = (usize, usize)>
+{
+    let infos = format.planes();
+    if planes.len() != infos.len() {
+        return Err(Error::PlaneLayout);
+    }
+    for ((len, stride), info) in planes.zip(infos) {
+        let rows = info.rows(height) as usize;
+        let row_bytes = info.row_bytes(width) as usize;
+        if rows > 0 && (stride < row_bytes || len < stride * (rows - 1) + row_bytes) {
+            return Err(Error::PlaneLayout);
+        }
+    }
+    Ok(())
+}
+
+fn max(bits: u32) -> f32 {
+    ((1u64 << bits) - 1) as f32
+}
+
+fn quantize(v: f32, bits: u32) -> u64 {
+    (v.clamp(0.0, 1.0) * max(bits) + 0.5) as u64
+}
+
+fn luma(r: f32, g: f32, b: f32) -> f32 {
+    0.299 * r + 0.587 * g + 0.114 * b
+}
+
+/// Y, Cb and Cr codes with `bits` bits each.
+fn to_yuv(c: Color, bits: u32) -> (u64, u64, u64) {
+    match c {
+        Color::Yuv(y, u, v) => (quantize(y, bits), quantize(u, bits), quantize(v, bits)),
+        Color::Rgb(r, g, b, _) => {
+            let scale = (1u64 << (bits - 8)) as f32;
+            let y = luma(r, g, b);
+            let cb = (b - y) / 1.772;
+            let cr = (r - y) / 1.402;
+            let code = |v: f32| (v * scale + 0.5).max(0.0).min(max(bits)) as u64;
+            (code(16.0 + 219.0 * y), code(128.0 + 224.0 * cb), code(128.0 + 224.0 * cr))
+        }
+    }
+}
+
+fn from_yuv(y: u64, cb: u64, cr: u64, bits: u32) -> Color {
+    let scale = (1u64 << (bits - 8)) as f32;
+    let y = (y as f32 / scale - 16.0) / 219.0;
+    let cb = (cb as f32 / scale - 128.0) / 224.0;
+    let cr = (cr as f32 / scale - 128.0) / 224.0;
+    let clamp = |v: f32| v.clamp(0.0, 1.0);
+    Color::Rgb(clamp(y + 1.402 * cr),
+               clamp(y - 0.344_136 * cb - 0.714_136 * cr),
+               clamp(y + 1.772 * cb),
+               1.0)
+}
+
+fn put(data: &mut [u8], at: usize, len: usize, v: u64) {
+    for i in 0..len {
+        data[at + i] = (v >> (i * 8)) as u8;
+    }
+}
+
+fn get(data: &[u8], at: usize, len: usize) -> u64 {
+    (0..len).fold(0, |v, i| v | (data[at + i] as u64) << (i * 8))
+}
+
+pub fn write(layout: &Layout, planes: &mut [PlaneMut], x: u32, y: u32, c: Color) {
+    let (x, y) = (x as usize, y as usize);
+    match *layout {
+        Layout::Rgb(ref channels, cpp) => {
+            let (r, g, b, a) = match c {
+                Color::Rgb(r, g, b, a) => (r, g, b, a),
+                Color::Yuv(l, _, _) => (l, l, l, 1.0),
+            };
+            let mut v = 0;
+            for &(ch, bits) in channels {
+                let c = match ch {
+                    b'R' => r,
+                    b'G' => g,
+                    b'B' => b,
+                    b'A' => a,
+                    b'C' => luma(r, g, b),
+                    _ => 0.0,
+                };
+                v = (v << bits) | quantize(c, bits);
+            }
+            let p = &mut planes[0];
+            put(p.data, y * p.stride + x * cpp, cpp, v);
+        }
+        Layout::Packed422(y0, u, y1, v) => {
+            let (l, cb, cr) = to_yuv(c, 8);
+            let p = &mut planes[0];
+            let pair = y * p.stride + (x & !1) * 2;
+            if pair + 4 > p.data.len() {
+                return;
+            }
+            if x & 1 == 0 {
+                p.data[pair + y0] = l as u8;
+                p.data[pair + u] = cb as u8;
+                p.data[pair + v] = cr as u8;
+            } else {
+                p.data[pair + y1] = l as u8;
+            }
+        }
+        Layout::Ayuv => {
+            let (l, cb, cr) = to_yuv(c, 8);
+            let a = match c {
+                Color::Rgb(_, _, _, a) => quantize(a, 8),
+                Color::Yuv(..) => 0xff,
+            };
+            let p = &mut planes[0];
+            put(p.data, y * p.stride + x * 4, 4, (a << 24) | (l << 16) | (cb << 8) | cr);
+        }
+        Layout::SemiPlanar { bits, size, cb_first, hsub, vsub } => {
+            // Samples sit in the high bits.
+            let shift = size as u32 * 8 - bits;
+            let (l, cb, cr) = to_yuv(c, bits);
+            {
+                let p = &mut planes[0];
+                put(p.data, y * p.stride + x * size, size, l << shift);
+            }
+            let (hsub, vsub) = (hsub as usize, vsub as usize);
+            if x % hsub == 0 && y % vsub == 0 {
+                let (first, second) = if cb_first { (cb, cr) } else { (cr, cb) };
+                let p = &mut planes[1];
+                let at = y / vsub * p.stride + x / hsub * 2 * size;
+                put(p.data, at, size, first << shift);
+                put(p.data, at + size, size, second << shift);
+            }
+        }
+        Layout::Planar { cb_first, hsub, vsub } => {
+            let (l, cb, cr) = to_yuv(c, 8);
+            {
+                let p = &mut planes[0];
+                p.data[y * p.stride + x] = l as u8;
+            }
+            let (hsub, vsub) = (hsub as usize, vsub as usize);
+            if x % hsub == 0 && y % vsub == 0 {
+                let (first, second) = if cb_first { (cb, cr) } else { (cr, cb) };
+                for &(i, v) in &[(1, first), (2, second)] {
+                    let p = &mut planes[i];
+                    p.data[y / vsub * p.stride + x / hsub] = v as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Reads a pixel as RGB.
+pub fn read(layout: &Layout, planes: &[PlaneRef], x: u32, y: u32) -> Color {
+    let (x, y) = (x as usize, y as usize);
+    match *layout {
+        Layout::Rgb(ref channels, cpp) => {
+            let p = &planes[0];
+            let mut v = get(p.data, y * p.stride + x * cpp, cpp);
+            let (mut r, mut g, mut b, mut a, mut grey) = (0.0, 0.0, 0.0, 1.0, None);
+            for &(ch, bits) in channels.iter().rev() {
+                let c = (v & ((1 << bits) - 1)) as f32 / max(bits);
+                v >>= bits;
+                match ch {
+                    b'R' => r = c,
+                    b'G' => g = c,
+                    b'B' => b = c,
+                    b'A' => a = c,
+                    b'C' => grey = Some(c),
+                    _ => (),
+                }
+            }
+            match grey {
+                Some(c) => Color::Rgb(c, c, c, 1.0),
+                None => Color::Rgb(r, g, b, a),
+            }
+        }
+        Layout::Packed422(y0, u, y1, v) => {
+            let p = &planes[0];
+            let pair = y * p.stride + (x & !1) * 2;
+            let l = p.data[pair + if x & 1 == 0 { y0 } else { y1 }];
+            from_yuv(l as u64, p.data[pair + u] as u64, p.data[pair + v] as u64, 8)
+        }
+        Layout::Ayuv => {
+            let p = &planes[0];
+            let v = get(p.data, y * p.stride + x * 4, 4);
+            match from_yuv((v >> 16) & 0xff, (v >> 8) & 0xff, v & 0xff, 8) {
+                Color::Rgb(r, g, b, _) => Color::Rgb(r, g, b, (v >> 24) as f32 / 255.0),
+                c => c,
+            }
+        }
+        Layout::SemiPlanar { bits, size, cb_first, hsub, vsub } => {
+            let shift = size as u32 * 8 - bits;
+            let l = get(planes[0].data, y * planes[0].stride + x * size, size) >> shift;
+            let p = &planes[1];
+            let at = y / vsub as usize * p.stride + x / hsub as usize * 2 * size;
+            let first = get(p.data, at, size) >> shift;
+            let second = get(p.data, at + size, size) >> shift;
+            let (cb, cr) = if cb_first { (first, second) } else { (second, first) };
+            from_yuv(l, cb, cr, bits)
+        }
+        Layout::Planar { cb_first, hsub, vsub } => {
+            let l = planes[0].data[y * planes[0].stride + x];
+            let at = |p: &PlaneRef| p.data[y / vsub as usize * p.stride + x / hsub as usize];
+            let (first, second) = (at(&planes[1]), at(&planes[2]));
+            let (cb, cr) = if cb_first { (first, second) } else { (second, first) };
+            from_yuv(l as u64, cb as u64, cr as u64, 8)
+        }
+    }
+}
+
+/// Planes of a `width` × `height` test image of `format`, each row
+/// followed by `pad` bytes and every byte set to `fill`.
+#[cfg(test)]
+pub fn test_planes(format: FourCC, width: u32, height: u32, pad: usize, fill: u8)
+                   -> Vec<(Vec<u8>, usize)> {
+    format.planes().iter().map(|p| {
+        let stride = p.row_bytes(width) as usize + pad;
+        (vec![fill; stride * p.rows(height) as usize], stride)
+    }).collect()
+}
+
+/// Writable views of planes made by `test_planes`.
+#[cfg(test)]
+pub fn planes_mut(bufs: &mut [(Vec<u8>, usize)]) -> Vec<PlaneMut<'_>> {
+    bufs.iter_mut().map(|&mut (ref mut data, stride)| PlaneMut { data, stride }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffi::FourCC;
+
+    const RED: Color = Color::Rgb(1.0, 0.0, 0.0, 1.0);
+    const WHITE: Color = Color::Rgb(1.0, 1.0, 1.0, 1.0);
+    const BLACK: Color = Color::Rgb(0.0, 0.0, 0.0, 1.0);
+
+    fn rgb(c: Color) -> (f32, f32, f32, f32) {
+        match c {
+            Color::Rgb(r, g, b, a) => (r, g, b, a),
+            Color::Yuv(..) => panic!("read returned YUV"),
+        }
+    }
+
+    fn assert_close(c: Color, r: f32, g: f32, b: f32) {
+        let (cr, cg, cb, _) = rgb(c);
+        let ok = (cr - r).abs() < 0.02 && (cg - g).abs() < 0.02 && (cb - b).abs() < 0.02;
+        assert!(ok, "{:?} is not close to ({}, {}, {})", c, r, g, b);
+    }
+
+    fn write_at(format: FourCC, bufs: &mut [(Vec<u8>, usize)], x: u32, y: u32, c: Color) {
+        let layout = Layout::of(format).unwrap();
+        write(&layout, &mut planes_mut(bufs), x, y, c);
+    }
+
+    fn read_at(format: FourCC, bufs: &[(Vec<u8>, usize)], x: u32, y: u32) -> Color {
+        let layout = Layout::of(format).unwrap();
+        let planes: Vec<PlaneRef> = bufs.iter()
+            .map(|&(ref data, stride)| PlaneRef { data, stride })
+            .collect();
+        read(&layout, &planes, x, y)
+    }
+
+    #[test]
+    fn bt601_limited_range() {
+        assert_eq!(to_yuv(BLACK, 8), (16, 128, 128));
+        assert_eq!(to_yuv(WHITE, 8), (235, 128, 128));
+        assert_eq!(to_yuv(RED, 8), (81, 90, 240));
+        assert_eq!(to_yuv(Color::Rgb(0.0, 1.0, 0.0, 1.0), 8), (145, 54, 34));
+        assert_eq!(to_yuv(Color::Rgb(0.0, 0.0, 1.0, 1.0), 8), (41, 240, 110));
+        assert_eq!(to_yuv(WHITE, 10), (940, 512, 512));
+        assert_close(from_yuv(81, 90, 240, 8), 1.0, 0.0, 0.0);
+        assert_close(from_yuv(16, 128, 128, 8), 0.0, 0.0, 0.0);
+        // Codes outside the nominal range clamp.
+        assert_close(from_yuv(255, 128, 128, 8), 1.0, 1.0, 1.0);
+        assert_close(from_yuv(0, 128, 128, 8), 0.0, 0.0, 0.0);
+    }
+
+    #[test]
+    fn rgb_packing() {
+        let mut bufs = test_planes(FourCC::XRGB8888, 1, 1, 0, 0);
+        write_at(FourCC::XRGB8888, &mut bufs, 0, 0, RED);
+        assert_eq!(bufs[0].0, [0, 0, 0xff, 0]);
+
+        let mut bufs = test_planes(FourCC::RGB565, 1, 1, 0, 0);
+        write_at(FourCC::RGB565, &mut bufs, 0, 0, RED);
+        assert_eq!(bufs[0].0, [0x00, 0xf8]);
+
+        let mut bufs = test_planes(FourCC::ABGR2101010, 1, 1, 0, 0);
+        write_at(FourCC::ABGR2101010, &mut bufs, 0, 0, Color::Rgb(1.0, 0.0, 0.0, 0.0));
+        assert_eq!(bufs[0].0, [0xff, 0x03, 0, 0]);
+        assert_close(read_at(FourCC::ABGR2101010, &bufs, 0, 0), 1.0, 0.0, 0.0);
+    }
+
+    #[test]
+    fn p010_packing() {
+        let mut bufs = test_planes(FourCC::P010, 2, 2, 0, 0);
+        write_at(FourCC::P010, &mut bufs, 0, 0, WHITE);
+        // 10 bit codes in the high bits of little endian 16 bit samples.
+        assert_eq!(&bufs[0].0[..2], &[0x00, 0xeb]);
+        assert_eq!(&bufs[1].0[..4], &[0x00, 0x80, 0x00, 0x80]);
+        write_at(FourCC::P010, &mut bufs, 1, 0, RED);
+        assert_eq!(&bufs[0].0[2..4], &(326u16 << 6).to_le_bytes());
+        assert_close(read_at(FourCC::P010, &bufs, 0, 0), 1.0, 1.0, 1.0);
+    }
+
+    #[test]
+    fn chroma_420() {
+        for &format in &[FourCC::NV12, FourCC::NV21, FourCC::YUV420, FourCC::YVU420] {
+            let mut bufs = test_planes(format, 4, 4, 0, 0);
+            write_at(format, &mut bufs, 2, 2, RED);
+            let chroma: Vec<Vec<u8>> = bufs[1..].iter().map(|b| b.0.clone()).collect();
+            // Only the top-left pixel of a block sets its chroma.
+            write_at(format, &mut bufs, 3, 3, WHITE);
+            write_at(format, &mut bufs, 2, 3, WHITE);
+            write_at(format, &mut bufs, 3, 2, WHITE);
+            let after: Vec<Vec<u8>> = bufs[1..].iter().map(|b| b.0.clone()).collect();
+            assert_eq!(chroma, after, "{:?}", format);
+            assert_close(read_at(format, &bufs, 2, 2), 1.0, 0.0, 0.0);
+            // The other pixels of the block still get their own luma.
+            assert_eq!(bufs[0].0[3 * bufs[0].1 + 3], 235, "{:?}", format);
+        }
+        let mut bufs = test_planes(FourCC::NV21, 2, 2, 0, 0);
+        write_at(FourCC::NV21, &mut bufs, 0, 0, RED);
+        assert_eq!(bufs[1].0, [240, 90]);
+    }
+
+    #[test]
+    fn chroma_422() {
+        let mut bufs = test_planes(FourCC::NV16, 4, 2, 0, 0);
+        write_at(FourCC::NV16, &mut bufs, 0, 1, RED);
+        // Every row has its own chroma.
+        assert_eq!(&bufs[1].0[..2], &[0, 0]);
+        assert_eq!(&bufs[1].0[4..6], &[90, 240]);
+        write_at(FourCC::NV16, &mut bufs, 1, 1, WHITE);
+        assert_eq!(&bufs[1].0[4..6], &[90, 240]);
+
+        let mut bufs = test_planes(FourCC::YUYV, 2, 1, 0, 0);
+        write_at(FourCC::YUYV, &mut bufs, 0, 0, RED);
+        write_at(FourCC::YUYV, &mut bufs, 1, 0, WHITE);
+        assert_eq!(bufs[0].0, [81, 90, 235, 240]);
+        let mut bufs = test_planes(FourCC::UYVY, 2, 1, 0, 0);
+        write_at(FourCC::UYVY, &mut bufs, 0, 0, RED);
+        write_at(FourCC::UYVY, &mut bufs, 1, 0, WHITE);
+        assert_eq!(bufs[0].0, [90, 81, 240, 235]);
+    }
+
+    #[test]
+    fn round_trip_every_format() {
+        for &format in FourCC::ALL {
+            let layout = match Layout::of(format) {
+                Some(layout) => layout,
+                None => continue,
+            };
+            let mut bufs = test_planes(format, 2, 2, 0, 0);
+            write_at(format, &mut bufs, 0, 0, Color::Rgb(0.8, 0.2, 0.4, 1.0));
+            let c = read_at(format, &bufs, 0, 0);
+            let (r, g, b, _) = rgb(c);
+            match layout {
+                // Too few bits or greyscale; just check it's a colour.
+                Layout::Rgb(ref channels, _)
+                    if channels.iter().any(|&(ch, bits)| ch == b'C' || bits < 4) =>
+                    assert!(r >= 0.0 && g >= 0.0 && b >= 0.0),
+                Layout::Rgb(..) => {
+                    assert!((r - 0.8).abs() < 0.07 && (g - 0.2).abs() < 0.07
+                            && (b - 0.4).abs() < 0.07, "{:?}: {:?}", format, c);
+                }
+                _ => assert_close(c, 0.8, 0.2, 0.4),
+            }
+        }
+    }
+
+    #[test]
+    fn layout_checks() {
+        let ok = |planes: &[(usize, usize)]| {
+            check_layout(FourCC::NV12, 4, 3, planes.iter().cloned()).is_ok()
+        };
+        assert!(ok(&[(12, 4), (8, 4)]));
+        // The last row only needs its pixels.
+        assert!(ok(&[(2 * 8 + 4, 8), (8 + 4, 8)]));
+        assert!(!ok(&[(11, 4), (8, 4)]));
+        assert!(!ok(&[(12, 3), (8, 4)]));
+        assert!(!ok(&[(12, 4)]));
+        assert!(check_layout(FourCC::NV12, 0, 0, [(0, 0), (0, 0)].iter().cloned()).is_ok());
+    }
+
+}
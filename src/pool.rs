@@ -0,0 +1,400 @@
+//! Recycling buffers instead of reallocating them every frame.
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use {Bo, BoFlags, BoFormat, Device, Error, FourCC};
+
+/// What a pooled buffer was allocated as.  Only buffers with equal keys
+/// are handed out in place of each other.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PoolKey {
+    pub width: u32,
+    pub height: u32,
+    pub format: FourCC,
+    /// Acceptable modifiers, or empty to let the driver pick without
+    /// explicit modifiers.
+    #[cfg_attr(feature = "serde", serde(with = "::serde_impls::modifiers"))]
+    pub modifiers: Vec<u64>,
+    pub flags: BoFlags,
+}
+
+impl PoolKey {
+    pub fn new(width: u32, height: u32, format: FourCC, flags: BoFlags) -> PoolKey {
+        PoolKey {
+            width,
+            height,
+            format,
+            modifiers: Vec::new(),
+            flags,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PoolStats {
+    /// Buffers handed out and not yet returned.
+    pub in_flight: usize,
+    /// Buffers waiting in the pool.
+    pub idle: usize,
+    pub allocations: u64,
+    /// Acquisitions served from the pool.
+    pub reuses: u64,
+    pub evictions: u64,
+}
+
+struct Idle<T> {
+    buf: T,
+    since: Instant,
+}
+
+/// The bookkeeping of a pool, kept apart from its device.  `T` is `Bo`
+/// except in tests.
+struct Buffers<T> {
+    idle: HashMap<PoolKey, VecDeque<Idle<T>>>,
+    in_flight: HashMap<PoolKey, usize>,
+    max_idle: usize,
+    max_idle_age: Option<Duration>,
+    stats: PoolStats,
+}
+
+struct PoolInner {
+    device: Device,
+    buffers: Buffers<Bo>,
+}
+
+/// A pool of buffers allocated from one `Device`.
+///
+/// `acquire` hands out a `PooledBo`, which goes back to the pool when it
+/// is dropped; until then it counts as in flight.  Idle buffers are
+/// evicted oldest first once there are more than `max_idle` of them, or
+/// once they have been idle longer than `max_idle_age`.  Eviction happens
+/// on every acquire and release, or explicitly through `evict`.
+///
+/// The pool is cheap to clone and may be shared between threads.
+#[derive(Clone)]
+pub struct BoPool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+/// A buffer on loan from a `BoPool`.
+pub struct PooledBo {
+    bo: Option<Bo>,
+    key: PoolKey,
+    pool: Weak<Mutex<PoolInner>>,
+}
+
+fn lock(inner: &Mutex<PoolInner>) -> MutexGuard<'_, PoolInner> {
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl BoPool {
+    /// A pool keeping at most 8 idle buffers, however old.
+    pub fn new(device: Device) -> BoPool {
+        BoPool {
+            inner: Arc::new(Mutex::new(PoolInner {
+                device,
+                buffers: Buffers::new(),
+            })),
+        }
+    }
+
+    pub fn set_max_idle(&self, max_idle: usize) {
+        let evicted = {
+            let mut inner = lock(&self.inner);
+            inner.buffers.max_idle = max_idle;
+            inner.buffers.evict(Instant::now())
+        };
+        drop(evicted);
+    }
+
+    pub fn set_max_idle_age(&self, age: Option<Duration>) {
+        let evicted = {
+            let mut inner = lock(&self.inner);
+            inner.buffers.max_idle_age = age;
+            inner.buffers.evict(Instant::now())
+        };
+        drop(evicted);
+    }
+
+    /// A buffer matching `key`, reused if one is idle and allocated
+    /// otherwise.
+    pub fn acquire(&self, key: &PoolKey) -> Result<PooledBo, Error> {
+        let (reused, evicted, device) = {
+            let mut inner = lock(&self.inner);
+            let evicted = inner.buffers.evict(Instant::now());
+            let reused = inner.buffers.reuse(key);
+            (reused, evicted, inner.device.clone())
+        };
+        // Freed and allocated buffers go through the device lock, so do
+        // that without holding ours.
+        drop(evicted);
+        let allocated = reused.is_none();
+        let bo = match reused {
+            Some(bo) => bo,
+            None => device.bo_create_with_modifiers(key.width, key.height,
+                                                    BoFormat::FourCC(key.format),
+                                                    &key.modifiers, key.flags)?,
+        };
+        lock(&self.inner).buffers.hand_out(key, allocated);
+        Ok(PooledBo {
+            bo: Some(bo),
+            key: key.clone(),
+            pool: Arc::downgrade(&self.inner),
+        })
+    }
+
+    /// Drops idle buffers that are over the limits.
+    pub fn evict(&self) {
+        let evicted = lock(&self.inner).buffers.evict(Instant::now());
+        drop(evicted);
+    }
+
+    /// Drops every idle buffer.
+    pub fn clear(&self) {
+        let evicted = lock(&self.inner).buffers.clear();
+        drop(evicted);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        lock(&self.inner).buffers.stats
+    }
+
+    /// Number of buffers with `key` currently handed out.
+    pub fn in_flight(&self, key: &PoolKey) -> usize {
+        lock(&self.inner).buffers.in_flight.get(key).cloned().unwrap_or(0)
+    }
+}
+
+impl<T> Buffers<T> {
+    /// At most 8 idle buffers, however old.
+    fn new() -> Buffers<T> {
+        Buffers {
+            idle: HashMap::new(),
+            in_flight: HashMap::new(),
+            max_idle: 8,
+            max_idle_age: None,
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// The most recently released idle buffer matching `key`.
+    fn reuse(&mut self, key: &PoolKey) -> Option<T> {
+        let buf = self.idle.get_mut(key).and_then(|q| q.pop_back())?;
+        self.stats.idle -= 1;
+        self.stats.reuses += 1;
+        Some(buf.buf)
+    }
+
+    /// Counts a buffer as handed out, reused or newly `allocated`.
+    fn hand_out(&mut self, key: &PoolKey, allocated: bool) {
+        *self.in_flight.entry(key.clone()).or_insert(0) += 1;
+        self.stats.in_flight += 1;
+        if allocated {
+            self.stats.allocations += 1;
+        }
+    }
+
+    /// Stops counting a buffer as handed out.
+    fn returned(&mut self, key: &PoolKey) {
+        if let Some(n) = self.in_flight.get_mut(key) {
+            *n -= 1;
+        }
+        self.in_flight.retain(|_, n| *n > 0);
+        self.stats.in_flight -= 1;
+    }
+
+    /// Removes idle buffers over the limits and returns them, to be
+    /// dropped after the pool lock is released.
+    fn evict(&mut self, now: Instant) -> Vec<T> {
+        let mut evicted = Vec::new();
+        if let Some(age) = self.max_idle_age {
+            for queue in self.idle.values_mut() {
+                // Queues are ordered oldest first.
+                while queue.front().is_some_and(|i| now.duration_since(i.since) > age) {
+                    evicted.push(queue.pop_front().unwrap().buf);
+                }
+            }
+        }
+        while self.stats.idle - evicted.len() > self.max_idle {
+            let oldest = self.idle.iter()
+                .filter_map(|(k, q)| q.front().map(|i| (k.clone(), i.since)))
+                .min_by_key(|&(_, since)| since)
+                .map(|(k, _)| k);
+            match oldest {
+                Some(k) => evicted.push(self.idle.get_mut(&k).unwrap().pop_front().unwrap().buf),
+                None => break,
+            }
+        }
+        self.idle.retain(|_, q| !q.is_empty());
+        self.stats.idle -= evicted.len();
+        self.stats.evictions += evicted.len() as u64;
+        evicted
+    }
+
+    /// Removes every idle buffer.
+    fn clear(&mut self) -> Vec<T> {
+        self.stats.evictions += self.stats.idle as u64;
+        self.stats.idle = 0;
+        self.idle.drain().flat_map(|(_, q)| q).map(|i| i.buf).collect()
+    }
+
+    fn release(&mut self, key: PoolKey, buf: T, now: Instant) -> Vec<T> {
+        self.returned(&key);
+        self.idle.entry(key).or_default()
+            .push_back(Idle { buf, since: now });
+        self.stats.idle += 1;
+        self.evict(now)
+    }
+}
+
+impl PooledBo {
+    pub fn key(&self) -> &PoolKey {
+        &self.key
+    }
+
+    /// Takes the buffer out of the pool for good.
+    pub fn detach(mut self) -> Bo {
+        let bo = self.bo.take().unwrap();
+        if let Some(pool) = self.pool.upgrade() {
+            lock(&pool).buffers.returned(&self.key);
+        }
+        bo
+    }
+}
+
+impl Deref for PooledBo {
+    type Target = Bo;
+    fn deref(&self) -> &Bo {
+        self.bo.as_ref().unwrap()
+    }
+}
+impl DerefMut for PooledBo {
+    fn deref_mut(&mut self) -> &mut Bo {
+        self.bo.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBo {
+    fn drop(&mut self) {
+        if let (Some(bo), Some(pool)) = (self.bo.take(), self.pool.upgrade()) {
+            let evicted = lock(&pool).buffers.release(self.key.clone(), bo, Instant::now());
+            drop(evicted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(width: u32) -> PoolKey {
+        PoolKey::new(width, 16, FourCC::XRGB8888, BoFlags::RENDERING)
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// Hands out `buf` for `key` and releases it at `at`.
+    fn cycle(b: &mut Buffers<u32>, key: &PoolKey, buf: u32, at: Instant) -> Vec<u32> {
+        b.hand_out(key, true);
+        b.release(key.clone(), buf, at)
+    }
+
+    #[test]
+    fn returned_buffers_are_reused() {
+        let mut b = Buffers::new();
+        let now = Instant::now();
+        b.hand_out(&key(1), true);
+        b.hand_out(&key(1), true);
+        assert_eq!(b.stats, PoolStats { in_flight: 2, allocations: 2, ..PoolStats::default() });
+
+        assert!(b.release(key(1), 10, now).is_empty());
+        assert_eq!(b.in_flight[&key(1)], 1);
+        assert_eq!(b.stats.in_flight, 1);
+        assert_eq!(b.stats.idle, 1);
+
+        assert_eq!(b.reuse(&key(2)), None);
+        assert_eq!(b.reuse(&key(1)), Some(10));
+        b.hand_out(&key(1), false);
+        assert_eq!(b.stats, PoolStats {
+            in_flight: 2, idle: 0, allocations: 2, reuses: 1, evictions: 0,
+        });
+
+        // Detached buffers just stop counting.
+        b.returned(&key(1));
+        b.returned(&key(1));
+        assert!(b.in_flight.is_empty());
+        assert_eq!(b.stats.in_flight, 0);
+    }
+
+    #[test]
+    fn newest_reused_first() {
+        let mut b = Buffers::new();
+        let now = Instant::now();
+        cycle(&mut b, &key(1), 1, now);
+        cycle(&mut b, &key(1), 2, now + ms(1));
+        assert_eq!(b.reuse(&key(1)), Some(2));
+        assert_eq!(b.reuse(&key(1)), Some(1));
+        assert_eq!(b.reuse(&key(1)), None);
+    }
+
+    #[test]
+    fn evicts_oldest_over_max_idle() {
+        let mut b = Buffers::new();
+        b.max_idle = 2;
+        let now = Instant::now();
+        assert!(cycle(&mut b, &key(1), 1, now).is_empty());
+        assert!(cycle(&mut b, &key(2), 2, now + ms(1)).is_empty());
+        assert_eq!(cycle(&mut b, &key(1), 3, now + ms(2)), [1]);
+        assert_eq!(cycle(&mut b, &key(3), 4, now + ms(3)), [2]);
+        assert_eq!(b.stats.idle, 2);
+        assert_eq!(b.stats.evictions, 2);
+        // Emptied queues are dropped.
+        assert!(!b.idle.contains_key(&key(2)));
+
+        b.max_idle = 0;
+        let mut evicted = b.evict(now + ms(4));
+        evicted.sort();
+        assert_eq!(evicted, [3, 4]);
+        assert!(b.idle.is_empty());
+        assert_eq!(b.stats.idle, 0);
+        assert_eq!(b.stats.evictions, 4);
+    }
+
+    #[test]
+    fn evicts_by_age() {
+        let mut b = Buffers::new();
+        b.max_idle_age = Some(ms(10));
+        let now = Instant::now();
+        cycle(&mut b, &key(1), 1, now);
+        cycle(&mut b, &key(1), 2, now + ms(8));
+        cycle(&mut b, &key(2), 3, now + ms(9));
+        assert!(b.evict(now + ms(10)).is_empty());
+        assert_eq!(b.evict(now + ms(15)), [1]);
+        let mut evicted = b.evict(now + ms(20));
+        evicted.sort();
+        assert_eq!(evicted, [2, 3]);
+        assert_eq!(b.stats.idle, 0);
+        assert_eq!(b.stats.evictions, 3);
+    }
+
+    #[test]
+    fn clear_evicts_all_idle() {
+        let mut b = Buffers::new();
+        let now = Instant::now();
+        cycle(&mut b, &key(1), 1, now);
+        cycle(&mut b, &key(2), 2, now);
+        b.hand_out(&key(1), true);
+        let mut evicted = b.clear();
+        evicted.sort();
+        assert_eq!(evicted, [1, 2]);
+        assert_eq!(b.stats, PoolStats {
+            in_flight: 1, idle: 0, allocations: 3, reuses: 0, evictions: 2,
+        });
+    }
+}
@@ -0,0 +1,381 @@
+//! `serde` support, enabled by the `serde` feature.
+//!
+//! Human readable formats get stable text encodings:
+//!
+//! * `FourCC` as its four character code, e.g. `"XR24"`; variant names
+//!   like `"XRGB8888"` are accepted too.
+//! * `BoFormat` as the code of its fourcc, except for the legacy
+//!   `BoFormat::XRGB8888` and `BoFormat::ARGB8888`, which are
+//!   `"gbm:XRGB8888"` and `"gbm:ARGB8888"`.
+//! * `BoFlags` as a list of flag names in the `Display` spelling, e.g.
+//!   `["scanout", "rendering"]`.  A single `|` separated string is
+//!   accepted too.
+//! * Modifiers as hex strings, e.g. `"0x0100000000000001"`.
+//!
+//! Binary formats get the raw numbers instead.
+
+use std::fmt;
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeSeq, SerializeStruct, Serializer};
+
+use {BoFlags, BoFormat, Dmabuf, DmabufPlane, FourCC};
+
+impl Serialize for FourCC {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(self.code().trim_end())
+        } else {
+            s.serialize_u32(*self as u32)
+        }
+    }
+}
+
+fn parse_fourcc(v: &str) -> Option<FourCC> {
+    if v.len() <= 4 {
+        let mut code = 0;
+        for (i, b) in format!("{:<4}", v).bytes().enumerate() {
+            code |= (b as u32) << (i * 8);
+        }
+        if let Some(f) = FourCC::from_u32(code) {
+            return Some(f);
+        }
+    }
+    FourCC::ALL.iter().cloned().find(|f| f.name() == v)
+}
+
+struct FourCCVisitor;
+
+impl<'de> Visitor<'de> for FourCCVisitor {
+    type Value = FourCC;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a fourcc code")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<FourCC, E> {
+        parse_fourcc(v).ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<FourCC, E> {
+        if v > u32::MAX as u64 {
+            return Err(E::invalid_value(de::Unexpected::Unsigned(v), &self));
+        }
+        FourCC::from_u32(v as u32)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for FourCC {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<FourCC, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_str(FourCCVisitor)
+        } else {
+            d.deserialize_u32(FourCCVisitor)
+        }
+    }
+}
+
+const LEGACY_XRGB8888: &str = "gbm:XRGB8888";
+const LEGACY_ARGB8888: &str = "gbm:ARGB8888";
+/// Binary encoding of the legacy formats, outside the fourcc space.
+const LEGACY_XRGB8888_RAW: u32 = 0;
+const LEGACY_ARGB8888_RAW: u32 = 1;
+
+impl Serialize for BoFormat {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match (*self, s.is_human_readable()) {
+            (BoFormat::XRGB8888, true) => s.serialize_str(LEGACY_XRGB8888),
+            (BoFormat::ARGB8888, true) => s.serialize_str(LEGACY_ARGB8888),
+            (BoFormat::XRGB8888, false) => s.serialize_u32(LEGACY_XRGB8888_RAW),
+            (BoFormat::ARGB8888, false) => s.serialize_u32(LEGACY_ARGB8888_RAW),
+            (BoFormat::FourCC(f), _) => f.serialize(s),
+        }
+    }
+}
+
+struct BoFormatVisitor;
+
+impl<'de> Visitor<'de> for BoFormatVisitor {
+    type Value = BoFormat;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a fourcc code or a legacy gbm format")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<BoFormat, E> {
+        match v {
+            LEGACY_XRGB8888 => Ok(BoFormat::XRGB8888),
+            LEGACY_ARGB8888 => Ok(BoFormat::ARGB8888),
+            _ => FourCCVisitor.visit_str(v).map(BoFormat::FourCC),
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<BoFormat, E> {
+        match v {
+            v if v == LEGACY_XRGB8888_RAW as u64 => Ok(BoFormat::XRGB8888),
+            v if v == LEGACY_ARGB8888_RAW as u64 => Ok(BoFormat::ARGB8888),
+            _ => FourCCVisitor.visit_u64(v).map(BoFormat::FourCC),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BoFormat {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<BoFormat, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_str(BoFormatVisitor)
+        } else {
+            d.deserialize_u32(BoFormatVisitor)
+        }
+    }
+}
+
+impl Serialize for BoFlags {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if !s.is_human_readable() {
+            return s.serialize_u32(self.bits());
+        }
+        let names = if self.is_empty() { String::new() } else { self.to_string() };
+        let names: Vec<&str> = names.split('|').filter(|n| !n.is_empty()).collect();
+        let mut seq = s.serialize_seq(Some(names.len()))?;
+        for name in names {
+            seq.serialize_element(name)?;
+        }
+        seq.end()
+    }
+}
+
+struct BoFlagsVisitor;
+
+impl<'de> Visitor<'de> for BoFlagsVisitor {
+    type Value = BoFlags;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of usage flag names")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<BoFlags, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<BoFlags, E> {
+        if v > u32::MAX as u64 {
+            return Err(E::invalid_value(de::Unexpected::Unsigned(v), &self));
+        }
+        Ok(BoFlags::from_bits(v as u32))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<BoFlags, A::Error> {
+        let mut flags = BoFlags::empty();
+        while let Some(name) = seq.next_element::<String>()? {
+            flags |= name.parse::<BoFlags>().map_err(de::Error::custom)?;
+        }
+        Ok(flags)
+    }
+}
+
+impl<'de> Deserialize<'de> for BoFlags {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<BoFlags, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_any(BoFlagsVisitor)
+        } else {
+            d.deserialize_u32(BoFlagsVisitor)
+        }
+    }
+}
+
+/// `#[serde(with = "...")]` encoding of a single modifier.
+pub mod modifier {
+    use std::fmt;
+
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(m: &u64, s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(&format!("{:#018x}", m))
+        } else {
+            s.serialize_u64(*m)
+        }
+    }
+
+    pub struct ModifierVisitor;
+
+    impl<'de> Visitor<'de> for ModifierVisitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a hex format modifier")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<u64, E> {
+            let hex = v.trim_start_matches("0x").trim_start_matches("0X");
+            if hex.len() == v.len() {
+                return Err(E::invalid_value(de::Unexpected::Str(v), &self));
+            }
+            u64::from_str_radix(hex, 16)
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<u64, E> {
+            Ok(v)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        if d.is_human_readable() {
+            d.deserialize_any(ModifierVisitor)
+        } else {
+            d.deserialize_u64(ModifierVisitor)
+        }
+    }
+}
+
+/// `#[serde(with = "...")]` encoding of a list of modifiers.
+pub mod modifiers {
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::{SerializeSeq, Serializer};
+
+    #[derive(Deserialize)]
+    struct Modifier(#[serde(with = "super::modifier")] u64);
+
+    pub fn serialize<S: Serializer>(ms: &Vec<u64>, s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(ms.len()))?;
+        for m in ms {
+            seq.serialize_element(&Hex(*m))?;
+        }
+        seq.end()
+    }
+
+    pub struct Hex(pub u64);
+
+    impl ::serde::Serialize for Hex {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            super::modifier::serialize(&self.0, s)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u64>, D::Error> {
+        let ms = Vec::<Modifier>::deserialize(d)?;
+        Ok(ms.into_iter().map(|m| m.0).collect())
+    }
+}
+
+/// Describes the layout only; the fds have to travel separately, e.g.
+/// with `ipc::send_dmabuf`.
+impl Serialize for Dmabuf {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut st = s.serialize_struct("Dmabuf", 5)?;
+        st.serialize_field("width", &self.width)?;
+        st.serialize_field("height", &self.height)?;
+        st.serialize_field("format", &self.format)?;
+        st.serialize_field("modifier", &modifiers::Hex(self.modifier))?;
+        st.serialize_field("planes", &self.planes)?;
+        st.end()
+    }
+}
+
+impl Serialize for DmabufPlane {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut st = s.serialize_struct("DmabufPlane", 2)?;
+        st.serialize_field("offset", &self.offset)?;
+        st.serialize_field("stride", &self.stride)?;
+        st.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use self::serde_json::{from_str, json, to_value};
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Modifiers {
+        #[serde(with = "modifier")]
+        one: u64,
+        #[serde(with = "modifiers")]
+        many: Vec<u64>,
+    }
+
+    #[test]
+    fn fourcc() {
+        for &f in FourCC::ALL {
+            let json = serde_json::to_string(&f).unwrap();
+            assert_eq!(from_str::<FourCC>(&json).unwrap(), f);
+        }
+        assert_eq!(to_value(FourCC::XRGB8888).unwrap(), json!("XR24"));
+        assert_eq!(to_value(FourCC::C8).unwrap(), json!("C8"));
+        assert_eq!(from_str::<FourCC>("\"XRGB8888\"").unwrap(), FourCC::XRGB8888);
+        assert!(from_str::<FourCC>("\"QQQQ\"").is_err());
+        assert!(from_str::<FourCC>("\"XR24 \"").is_err());
+        assert!(from_str::<FourCC>("875713112").is_err());
+    }
+
+    #[test]
+    fn bo_format() {
+        for &f in &[BoFormat::XRGB8888, BoFormat::ARGB8888, BoFormat::FourCC(FourCC::NV12)] {
+            let json = serde_json::to_string(&f).unwrap();
+            assert_eq!(from_str::<BoFormat>(&json).unwrap(), f);
+        }
+        assert_eq!(to_value(BoFormat::ARGB8888).unwrap(), json!("gbm:ARGB8888"));
+        assert_eq!(to_value(BoFormat::FourCC(FourCC::ARGB8888)).unwrap(), json!("AR24"));
+        assert!(from_str::<BoFormat>("\"gbm:NV12\"").is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let flags = BoFlags::SCANOUT | BoFlags::RENDERING;
+        assert_eq!(to_value(flags).unwrap(), json!(["scanout", "rendering"]));
+        assert_eq!(to_value(BoFlags::empty()).unwrap(), json!([]));
+        for &f in &[flags, BoFlags::empty(), BoFlags::LINEAR] {
+            let json = serde_json::to_string(&f).unwrap();
+            assert_eq!(from_str::<BoFlags>(&json).unwrap(), f);
+        }
+        assert_eq!(from_str::<BoFlags>("\"scanout | LINEAR\"").unwrap(),
+                   BoFlags::SCANOUT | BoFlags::LINEAR);
+    }
+
+    #[test]
+    fn unknown_flag_bits() {
+        let flags = BoFlags::SCANOUT | BoFlags::from_bits(1 << 30);
+        assert_eq!(to_value(flags).unwrap(), json!(["scanout", "0x40000000"]));
+        let json = serde_json::to_string(&flags).unwrap();
+        assert_eq!(from_str::<BoFlags>(&json).unwrap(), flags);
+    }
+
+    #[test]
+    fn invalid_flags() {
+        assert!(from_str::<BoFlags>("[\"scanout\", \"sideways\"]").is_err());
+        assert!(from_str::<BoFlags>("\"scanout|0xnope\"").is_err());
+        assert!(from_str::<BoFlags>("[1]").is_err());
+        assert!(from_str::<BoFlags>("8589934592").is_err());
+        assert!(from_str::<BoFlags>("{}").is_err());
+    }
+
+    #[test]
+    fn modifier_round_trip() {
+        let m = Modifiers { one: 0x0100_0000_0000_0001, many: vec![0, u64::MAX] };
+        assert_eq!(to_value(&m).unwrap(), json!({
+            "one": "0x0100000000000001",
+            "many": ["0x0000000000000000", "0xffffffffffffffff"],
+        }));
+        let json = serde_json::to_string(&m).unwrap();
+        assert_eq!(from_str::<Modifiers>(&json).unwrap(), m);
+        // Numbers and short hex are accepted too.
+        assert_eq!(from_str::<Modifiers>(r#"{"one": 7, "many": ["0X1f"]}"#).unwrap(),
+                   Modifiers { one: 7, many: vec![0x1f] });
+    }
+
+    #[test]
+    fn invalid_modifiers() {
+        let parse = |one: &str| from_str::<Modifiers>(&format!(r#"{{"one": {}, "many": []}}"#, one));
+        assert!(parse("\"123\"").is_err());
+        assert!(parse("\"0x\"").is_err());
+        assert!(parse("\"0xfg\"").is_err());
+        assert!(parse("\"0x10000000000000000\"").is_err());
+        assert!(parse("-1").is_err());
+        assert!(from_str::<Modifiers>(r#"{"one": 0, "many": ["zero"]}"#).is_err());
+    }
+}
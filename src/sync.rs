@@ -0,0 +1,121 @@
+//! Per-device serialization of calls into libgbm.
+
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
+
+/// A re-entrant lock.  Callbacks run by libgbm while the lock is held
+/// (user data destructors, for instance) may drop other buffers of the
+/// same device, which takes the lock again on the same thread.
+#[derive(Debug, Default)]
+pub struct DeviceLock {
+    state: Mutex<LockState>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    owner: Option<ThreadId>,
+    depth: usize,
+}
+
+#[must_use]
+pub struct DeviceLockGuard<'a> {
+    lock: &'a DeviceLock,
+}
+
+impl DeviceLock {
+    pub fn new() -> DeviceLock {
+        DeviceLock::default()
+    }
+
+    pub fn lock(&self) -> DeviceLockGuard<'_> {
+        let me = thread::current().id();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.owner.is_some_and(|owner| owner != me) {
+            state = self.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.owner = Some(me);
+        state.depth += 1;
+        DeviceLockGuard { lock: self }
+    }
+}
+
+impl<'a> Drop for DeviceLockGuard<'a> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.depth -= 1;
+        if state.depth == 0 {
+            state.owner = None;
+            self.lock.cond.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    /// Whether another thread can take `lock` within a short while.
+    fn lockable_elsewhere(lock: &Arc<DeviceLock>) -> bool {
+        let (tx, rx) = mpsc::channel();
+        let lock = lock.clone();
+        thread::spawn(move || {
+            let _guard = lock.lock();
+            let _ = tx.send(());
+        });
+        rx.recv_timeout(Duration::from_millis(200)).is_ok()
+    }
+
+    fn depth(lock: &DeviceLock) -> usize {
+        lock.state.lock().unwrap().depth
+    }
+
+    #[test]
+    fn reentrant() {
+        let lock = DeviceLock::new();
+        let outer = lock.lock();
+        let inner = lock.lock();
+        assert_eq!(depth(&lock), 2);
+        drop(inner);
+        assert_eq!(lock.state.lock().unwrap().owner, Some(thread::current().id()));
+        drop(outer);
+        assert_eq!(depth(&lock), 0);
+        assert_eq!(lock.state.lock().unwrap().owner, None);
+    }
+
+    #[test]
+    fn excludes_other_threads() {
+        let lock = Arc::new(DeviceLock::new());
+        let guard = lock.lock();
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _guard = lock.lock();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(guard);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn released_after_panic() {
+        let lock = Arc::new(DeviceLock::new());
+        let panicker = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _outer = lock.lock();
+                let _inner = lock.lock();
+                panic!("while locked");
+            })
+        };
+        assert!(panicker.join().is_err());
+        assert_eq!(depth(&lock), 0);
+        assert!(lockable_elsewhere(&lock));
+    }
+}
@@ -0,0 +1,257 @@
+//! Fences as sync_file fds, for bridging the implicit sync of dmabufs
+//! with explicit sync protocols.
+//!
+//! Exporting snapshots the fences attached to a dmabuf at that moment;
+//! importing attaches a fence to it, so later implicit-sync users wait
+//! for it too.  Both need Linux 6.0 or later.
+//!
+//! A sync_file polls readable once its fence has signaled, so it can be
+//! registered with an event loop through `AsRawFd` instead of calling
+//! `wait`.
+
+use std::cmp;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use dmabuf::sync_flags;
+use {ffi, Bo, Transfer};
+
+/// An owned sync_file fd.
+#[derive(Debug)]
+pub struct SyncFile {
+    fd: OwnedFd,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum FenceStatus {
+    Active,
+    Signaled,
+    /// Signaled with an error, a negative errno.
+    Error(i32),
+}
+
+impl FenceStatus {
+    fn from_raw(status: i32) -> FenceStatus {
+        match status {
+            0 => FenceStatus::Active,
+            s if s > 0 => FenceStatus::Signaled,
+            s => FenceStatus::Error(s),
+        }
+    }
+}
+
+/// One of the fences a sync_file is made of.
+#[derive(Clone, Debug)]
+pub struct FenceInfo {
+    /// Timeline the fence is on.
+    pub obj_name: String,
+    pub driver_name: String,
+    pub status: FenceStatus,
+    /// When the fence signaled, on `CLOCK_MONOTONIC`; 0 while active.
+    pub timestamp_ns: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncFileInfo {
+    pub name: String,
+    /// Signaled once all fences are.
+    pub status: FenceStatus,
+    pub fences: Vec<FenceInfo>,
+}
+
+fn c_name(name: &[u8]) -> String {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+impl SyncFile {
+    /// The fence the dmabuf `fd` implicitly carries for `transfer`:
+    /// reading waits for pending writes, writing waits for all pending
+    /// access.
+    pub fn export(fd: BorrowedFd, transfer: Transfer) -> io::Result<SyncFile> {
+        let mut arg = ffi::dma_buf_sync_file {
+            flags: sync_flags(transfer) as u32,
+            fd: -1,
+        };
+        let ret = unsafe {
+            libc::ioctl(fd.as_raw_fd(), ffi::DMA_BUF_IOCTL_EXPORT_SYNC_FILE as _, &mut arg)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SyncFile { fd: unsafe { OwnedFd::from_raw_fd(arg.fd) } })
+    }
+
+    /// Attaches this fence to the dmabuf `fd` as a read or write fence.
+    /// The fd stays ours.
+    pub fn import_into(&self, fd: BorrowedFd, transfer: Transfer) -> io::Result<()> {
+        let arg = ffi::dma_buf_sync_file {
+            flags: sync_flags(transfer) as u32,
+            fd: self.fd.as_raw_fd(),
+        };
+        let ret = unsafe {
+            libc::ioctl(fd.as_raw_fd(), ffi::DMA_BUF_IOCTL_IMPORT_SYNC_FILE as _, &arg)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits for the fence to signal, at most `timeout` if given.
+    /// Returns whether it signaled.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let ms = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    // Round up, so we never wake before the deadline.
+                    let ms = left.as_secs().saturating_mul(1000)
+                        + (left.subsec_nanos() as u64).div_ceil(1_000_000);
+                    cmp::min(ms, i32::MAX as u64) as i32
+                }
+            };
+            let mut pfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pfd, 1, ms) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue }
+                return Err(err);
+            }
+            if ret > 0 {
+                if pfd.revents & (libc::POLLERR | libc::POLLNVAL) != 0 {
+                    return Err(io::Error::other("polling sync_file failed"));
+                }
+                return Ok(true);
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Ok(false);
+            }
+        }
+    }
+
+    pub fn is_signaled(&self) -> io::Result<bool> {
+        self.wait(Some(Duration::from_secs(0)))
+    }
+
+    /// A new sync_file that signals once both `a` and `b` have.
+    pub fn merge(a: &SyncFile, b: &SyncFile) -> io::Result<SyncFile> {
+        let mut arg = ffi::sync_merge_data {
+            name: [0; 32],
+            fd2: b.fd.as_raw_fd(),
+            fence: -1,
+            flags: 0,
+            pad: 0,
+        };
+        arg.name[..5].copy_from_slice(b"merge");
+        let ret = unsafe {
+            libc::ioctl(a.fd.as_raw_fd(), ffi::SYNC_IOC_MERGE as _, &mut arg)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SyncFile { fd: unsafe { OwnedFd::from_raw_fd(arg.fence) } })
+    }
+
+    pub fn info(&self) -> io::Result<SyncFileInfo> {
+        let mut info: ffi::sync_file_info = unsafe { mem::zeroed() };
+        // The first call only counts the fences.
+        self.file_info(&mut info)?;
+        let mut fences: Vec<ffi::sync_fence_info> =
+            vec![unsafe { mem::zeroed() }; info.num_fences as usize];
+        info.sync_fence_info = fences.as_mut_ptr() as u64;
+        self.file_info(&mut info)?;
+        // Fences can't be added to a sync_file, but stay safe anyway.
+        fences.truncate(info.num_fences as usize);
+        Ok(SyncFileInfo {
+            name: c_name(&info.name),
+            status: FenceStatus::from_raw(info.status),
+            fences: fences.iter().map(|f| FenceInfo {
+                obj_name: c_name(&f.obj_name),
+                driver_name: c_name(&f.driver_name),
+                status: FenceStatus::from_raw(f.status),
+                timestamp_ns: f.timestamp_ns,
+            }).collect(),
+        })
+    }
+
+    fn file_info(&self, info: &mut ffi::sync_file_info) -> io::Result<()> {
+        let ret = unsafe {
+            libc::ioctl(self.fd.as_raw_fd(), ffi::SYNC_IOC_FILE_INFO as _, info as *mut _)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl From<OwnedFd> for SyncFile {
+    /// Takes `fd` as is; it is not checked to be a sync_file.
+    fn from(fd: OwnedFd) -> SyncFile {
+        SyncFile { fd }
+    }
+}
+
+impl From<SyncFile> for OwnedFd {
+    fn from(sync_file: SyncFile) -> OwnedFd {
+        sync_file.fd
+    }
+}
+
+impl AsFd for SyncFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for SyncFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SyncFile {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+impl FromRawFd for SyncFile {
+    unsafe fn from_raw_fd(fd: RawFd) -> SyncFile {
+        SyncFile { fd: OwnedFd::from_raw_fd(fd) }
+    }
+}
+
+impl Bo {
+    fn dmabuf_fd(&self) -> io::Result<OwnedFd> {
+        let fd = self.get_fd();
+        if fd < 0 {
+            return Err(io::Error::other("failed to export buffer"));
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// The fence to wait on before accessing the buffer for `transfer`,
+    /// e.g. `Transfer::Read` on a front buffer to learn when rendering
+    /// into it has finished.
+    pub fn export_sync_file(&self, transfer: Transfer) -> io::Result<SyncFile> {
+        SyncFile::export(self.dmabuf_fd()?.as_fd(), transfer)
+    }
+
+    /// Makes implicit-sync users of the buffer wait for `fence`, which
+    /// guards an access of the kind `transfer`.
+    pub fn import_sync_file(&self, fence: &SyncFile, transfer: Transfer) -> io::Result<()> {
+        fence.import_into(self.dmabuf_fd()?.as_fd(), transfer)
+    }
+}
@@ -0,0 +1,188 @@
+//! `tracing` instrumentation, enabled by the `tracing` feature.
+//!
+//! Every function here is a no-op without the feature, so call sites
+//! don't need their own `cfg`s.  Everything goes to the `gbm` target.
+//! Allocations, imports and mappings get a `debug` span, surface locks a
+//! `trace` one; a mapping's span lives as long as the `BoMapping`.  Spans
+//! are only entered while their own events are recorded, so nothing else
+//! the thread logs is attributed to them.  Results are events inside the
+//! span: buffer lifecycle at `debug`, per-frame surface traffic at
+//! `trace` and failures at `warn`, with the errno libgbm left behind.
+
+#![cfg_attr(not(feature = "tracing"), allow(unused_variables, dead_code))]
+
+use std::io;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+use {ffi, Bo, BoFlags, BoFormat, Surface, Transfer};
+
+/// The span of one operation, closed on drop.
+#[derive(Debug)]
+pub struct Op {
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+    #[cfg(feature = "tracing")]
+    start: Instant,
+}
+
+impl Op {
+    #[cfg(feature = "tracing")]
+    fn new(span: ::tracing::Span) -> Op {
+        Op { span, start: Instant::now() }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn elapsed_us(&self) -> u64 {
+        let d = self.start.elapsed();
+        d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+    }
+}
+
+/// A buffer allocation, with or without modifiers.
+#[derive(Copy, Clone, Debug)]
+pub struct BoCreate<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub format: BoFormat,
+    pub modifiers: &'a [u64],
+    pub flags: BoFlags,
+}
+
+/// The rectangle of a plane being mapped.
+#[derive(Copy, Clone, Debug)]
+pub struct MapRegion {
+    pub plane: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub transfer: Transfer,
+}
+
+pub fn bo_create(args: &BoCreate) -> Op {
+    #[cfg(feature = "tracing")]
+    {
+        let name = if args.modifiers.is_empty() { "bo_create" } else { "bo_create_with_modifiers" };
+        Op::new(debug_span!(target: "gbm", "bo_create", op = name, width = args.width,
+                            height = args.height, format = ?args.format,
+                            modifiers = ?args.modifiers, flags = %args.flags))
+    }
+    #[cfg(not(feature = "tracing"))]
+    Op {}
+}
+
+pub fn bo_created(op: &Op, res: Result<&Bo, &Option<io::Error>>) {
+    #[cfg(feature = "tracing")]
+    op.span.in_scope(|| match res {
+        Ok(bo) => debug!(target: "gbm", bo = ?bo.ptr,
+                         modifier = format_args!("{:#x}", bo.get_modifier()),
+                         elapsed_us = op.elapsed_us(), "created"),
+        Err(err) => warn!(target: "gbm", error = ?err, elapsed_us = op.elapsed_us(),
+                          "bo_create failed"),
+    })
+}
+
+pub fn bo_import(kind: &'static str, usage: BoFlags) -> Op {
+    #[cfg(feature = "tracing")]
+    {
+        Op::new(debug_span!(target: "gbm", "bo_import", kind, usage = %usage))
+    }
+    #[cfg(not(feature = "tracing"))]
+    Op {}
+}
+
+pub fn bo_imported(op: &Op, res: Result<&Bo, &Option<io::Error>>) {
+    #[cfg(feature = "tracing")]
+    op.span.in_scope(|| match res {
+        Ok(bo) => debug!(target: "gbm", bo = ?bo.ptr, width = bo.get_width(),
+                         height = bo.get_height(), format = ?bo.get_format(),
+                         elapsed_us = op.elapsed_us(), "imported"),
+        Err(err) => warn!(target: "gbm", error = ?err, elapsed_us = op.elapsed_us(),
+                          "bo_import failed"),
+    })
+}
+
+pub fn surface_create(width: u32, height: u32, format: BoFormat, flags: BoFlags) -> Op {
+    #[cfg(feature = "tracing")]
+    {
+        Op::new(debug_span!(target: "gbm", "surface_create", width, height,
+                            format = ?format, flags = %flags))
+    }
+    #[cfg(not(feature = "tracing"))]
+    Op {}
+}
+
+pub fn surface_created(op: &Op, res: Result<&Surface, &Option<io::Error>>) {
+    #[cfg(feature = "tracing")]
+    op.span.in_scope(|| match res {
+        Ok(s) => debug!(target: "gbm", surface = ?s.ptr, elapsed_us = op.elapsed_us(),
+                        "created"),
+        Err(err) => warn!(target: "gbm", error = ?err, elapsed_us = op.elapsed_us(),
+                          "surface_create failed"),
+    })
+}
+
+pub fn lock_front_buffer(surface: *mut ffi::gbm_surface) -> Op {
+    #[cfg(feature = "tracing")]
+    {
+        Op::new(trace_span!(target: "gbm", "lock_front_buffer", surface = ?surface))
+    }
+    #[cfg(not(feature = "tracing"))]
+    Op {}
+}
+
+pub fn locked(op: &Op, res: Option<(&Bo, u32)>) {
+    #[cfg(feature = "tracing")]
+    op.span.in_scope(|| match res {
+        Some((bo, age)) => trace!(target: "gbm", bo = ?bo.ptr, age,
+                                  elapsed_us = op.elapsed_us(), "locked"),
+        None => warn!(target: "gbm", elapsed_us = op.elapsed_us(), "lock_front_buffer failed"),
+    })
+}
+
+pub fn release_buffer(surface: *mut ffi::gbm_surface, bo: *mut ffi::gbm_bo) {
+    #[cfg(feature = "tracing")]
+    trace!(target: "gbm", surface = ?surface, bo = ?bo, "release_buffer");
+}
+
+pub fn map(bo: *mut ffi::gbm_bo, region: &MapRegion) -> Op {
+    #[cfg(feature = "tracing")]
+    {
+        Op::new(debug_span!(target: "gbm", "map", bo = ?bo, plane = region.plane,
+                            x = region.x, y = region.y, width = region.width,
+                            height = region.height, transfer = ?region.transfer))
+    }
+    #[cfg(not(feature = "tracing"))]
+    Op {}
+}
+
+pub fn mapped(op: &Op, res: Result<u32, &Option<io::Error>>) {
+    #[cfg(feature = "tracing")]
+    op.span.in_scope(|| match res {
+        Ok(stride) => debug!(target: "gbm", stride, elapsed_us = op.elapsed_us(), "mapped"),
+        Err(err) => warn!(target: "gbm", error = ?err, elapsed_us = op.elapsed_us(),
+                          "map failed"),
+    })
+}
+
+/// Ends a mapping, inside its span.
+pub fn unmap(op: &Op) {
+    #[cfg(feature = "tracing")]
+    op.span.in_scope(|| debug!(target: "gbm", elapsed_us = op.elapsed_us(), "unmap"));
+}
+
+pub fn bo_destroy(bo: *mut ffi::gbm_bo) {
+    #[cfg(feature = "tracing")]
+    debug!(target: "gbm", bo = ?bo, "bo_destroy");
+}
+
+pub fn surface_destroy(surface: *mut ffi::gbm_surface) {
+    #[cfg(feature = "tracing")]
+    debug!(target: "gbm", surface = ?surface, "surface_destroy");
+}
+
+pub fn device_destroy(device: *mut ffi::gbm_device) {
+    #[cfg(feature = "tracing")]
+    debug!(target: "gbm", device = ?device, "device_destroy");
+}
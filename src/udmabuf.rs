@@ -0,0 +1,127 @@
+//! Dmabufs backed by ordinary memory, through `/dev/udmabuf`.
+//!
+//! Useful for software rendering and for tests on machines without a
+//! GPU.  The memory lives in a sealed memfd that the kernel turns into a
+//! dmabuf; the memfd itself is closed again right away.
+
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+
+use libc;
+
+use {ffi, Bo, BoFlags, BoFormat, BoImport, Device, Dmabuf, DmabufPlane, FourCC};
+
+/// Row alignment of allocated planes, in bytes.
+pub const STRIDE_ALIGN: u32 = 64;
+
+/// Allocates dmabufs from memfds.
+#[derive(Debug)]
+pub struct UdmabufAllocator {
+    dev: OwnedFd,
+}
+
+fn sealed_memfd(size: u64) -> io::Result<OwnedFd> {
+    let name = CStr::from_bytes_with_nul(b"gbm-udmabuf\0").unwrap();
+    let fd = unsafe {
+        libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::ftruncate(fd.as_raw_fd(), size as libc::off_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // udmabuf insists the size can't shrink under it, and refuses memfds
+    // sealed against writing.
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+impl UdmabufAllocator {
+    /// Opens `/dev/udmabuf`, usually readable only by root or the `kvm`
+    /// group.
+    pub fn new() -> io::Result<UdmabufAllocator> {
+        let path = CStr::from_bytes_with_nul(b"/dev/udmabuf\0").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UdmabufAllocator { dev: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    /// A dmabuf of `size` bytes, rounded up to whole pages.
+    pub fn allocate_bytes(&self, size: u64) -> io::Result<OwnedFd> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let size = size.next_multiple_of(page);
+        let memfd = sealed_memfd(size)?;
+        let create = ffi::udmabuf_create {
+            memfd: memfd.as_raw_fd() as u32,
+            flags: ffi::UDMABUF_FLAGS_CLOEXEC,
+            offset: 0,
+            size,
+        };
+        let fd = unsafe {
+            libc::ioctl(self.dev.as_raw_fd(), ffi::UDMABUF_CREATE as _, &create)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// A linear image with every plane of `format` in one dmabuf, rows
+    /// aligned to `STRIDE_ALIGN`.  The memory starts out zeroed.
+    pub fn allocate(&self, width: u32, height: u32, format: FourCC) -> io::Result<Dmabuf> {
+        let mut layout = Vec::new();
+        let mut size = 0;
+        for info in format.planes() {
+            let stride = (info.row_bytes(width) as u64).next_multiple_of(STRIDE_ALIGN as u64);
+            layout.push((size, stride));
+            size += stride * info.rows(height) as u64;
+        }
+        if size > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "image too large"));
+        }
+        let fd = self.allocate_bytes(size)?;
+        let mut planes = Vec::new();
+        for &(offset, stride) in &layout {
+            planes.push(DmabufPlane {
+                fd: fd.try_clone()?,
+                offset: offset as u32,
+                stride: stride as u32,
+            });
+        }
+        Ok(Dmabuf {
+            width,
+            height,
+            format,
+            modifier: ffi::DRM_FORMAT_MOD_LINEAR,
+            planes,
+        })
+    }
+
+    /// Allocates like `allocate` and imports the result into `device`
+    /// with `BoImport::Fd`, which only takes single plane formats.
+    pub fn allocate_bo(&self, device: &Device, width: u32, height: u32, format: FourCC,
+                       usage: BoFlags) -> io::Result<Bo> {
+        if format.planes().len() != 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "BoImport::Fd takes single plane formats only"));
+        }
+        let dmabuf = self.allocate(width, height, format)?;
+        let import = BoImport::Fd {
+            fd: dmabuf.planes[0].fd.as_fd(),
+            width,
+            height,
+            stride: dmabuf.planes[0].stride,
+            format: BoFormat::FourCC(format),
+        };
+        device.bo_import(import, usage)
+            .map_err(|_| io::Error::other("failed to import udmabuf"))
+    }
+}